@@ -0,0 +1,569 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use flate2::read::GzDecoder;
+use indicatif::ProgressBar;
+use lzma_rust2::XzReader;
+use sevenz_rust2::{ArchiveReader, Password};
+
+use crate::entry::{self, StagedEntry};
+use crate::{ExtractOptions, limits};
+
+const ZIP_SIGNATURES: [&[u8]; 3] = [b"PK\x03\x04", b"PK\x05\x06", b"PK\x07\x08"];
+const GZIP_SIGNATURE: &[u8] = b"\x1f\x8b";
+const XZ_SIGNATURE: &[u8] = b"\xfd7zXZ\x00";
+const SEVEN_ZIP_SIGNATURE: &[u8] = b"7z\xbc\xaf\x27\x1c";
+const TAR_MAGIC: &[u8] = b"ustar";
+const TAR_MAGIC_OFFSET: usize = 257;
+
+/// Extensions of files whose signature is read to detect the archive format.
+/// Other extensions are not read, since zip-based formats like `.docx` and `.epub` have zip signatures.
+const SNIFFED_EXTENSIONS: [&str; 7] = ["7z", "gz", "tar", "tgz", "txz", "xz", "zip"];
+
+/// Number of bytes needed from the start of a file to detect the format.
+const SIGNATURE_LENGTH: usize = TAR_MAGIC_OFFSET + TAR_MAGIC.len();
+
+/// Windows attribute flag used by 7-Zip to mark Unix permissions in the high 16 bits.
+const SEVEN_ZIP_UNIX_EXTENSION: u32 = 0x8000;
+
+/// Supported archive formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+    TarXz,
+    SevenZip,
+}
+
+/// What to do with an archive after it has been extracted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ArchiveDisposition {
+    /// Move the archive to the trash
+    #[default]
+    Trash,
+    /// Delete the archive permanently
+    Delete,
+    /// Keep the archive
+    Keep,
+}
+
+/// A file or directory inside an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    /// Path inside the archive
+    pub name: String,
+    pub is_dir: bool,
+    /// Uncompressed size in bytes
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// Unix permission bits
+    pub mode: Option<u32>,
+}
+
+/// Sequential access to the entries of an archive.
+pub trait Archive {
+    /// Total uncompressed size of all entries, if known without reading the entry data.
+    fn total_size(&self) -> Option<u64>;
+
+    /// Call the visitor with each file and directory entry and its data in archive order.
+    /// Stops at the first error returned by the visitor.
+    fn for_each_entry(
+        &mut self,
+        visit: &mut dyn FnMut(&ArchiveEntry, &mut dyn Read) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()>;
+
+    /// List the file and directory entries in archive order.
+    /// Reads through the whole archive unless the format has an index of its entries.
+    fn list_entries(&mut self) -> anyhow::Result<Vec<ArchiveEntry>> {
+        let mut entries = Vec::new();
+        self.for_each_entry(&mut |entry, _| {
+            entries.push(entry.clone());
+            Ok(())
+        })?;
+        Ok(entries)
+    }
+}
+
+/// Tar archive read from an uncompressed or decompressing reader.
+pub struct TarArchive<R: Read> {
+    archive: tar::Archive<R>,
+}
+
+/// 7z archive.
+pub struct SevenZipArchive<R: Read + Seek> {
+    reader: ArchiveReader<R>,
+}
+
+impl ArchiveFormat {
+    /// Get the archive format from the file extension.
+    #[must_use]
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_lowercase();
+        // Compound extensions need to be checked before plain `.tar`
+        [
+            (".tar.gz", Self::TarGz),
+            (".tgz", Self::TarGz),
+            (".tar.xz", Self::TarXz),
+            (".txz", Self::TarXz),
+            (".tar", Self::Tar),
+            (".7z", Self::SevenZip),
+            (".zip", Self::Zip),
+        ]
+        .into_iter()
+        .find(|(extension, _)| name.ends_with(extension))
+        .map(|(_, format)| format)
+    }
+
+    /// Detect the archive format from the file signature,
+    /// for files without an extension or with an archive extension.
+    /// Returns `None` for other files and files that can not be read.
+    #[must_use]
+    pub fn detect(path: &Path) -> Option<Self> {
+        let sniffed = path.extension().is_none_or(|extension| {
+            SNIFFED_EXTENSIONS
+                .iter()
+                .any(|sniffed| extension.eq_ignore_ascii_case(sniffed))
+        });
+        if sniffed { Self::sniff(path) } else { None }
+    }
+
+    /// Detect the archive format from the file signature, whatever the file extension.
+    /// Compressed files are only detected as archives if they contain a tar archive.
+    /// Returns `None` for other files and files that can not be read.
+    #[must_use]
+    pub fn sniff(path: &Path) -> Option<Self> {
+        let prefix = read_prefix(File::open(path).ok()?)?;
+        if ZIP_SIGNATURES
+            .iter()
+            .any(|signature| prefix.starts_with(signature))
+        {
+            Some(Self::Zip)
+        } else if prefix.starts_with(SEVEN_ZIP_SIGNATURE) {
+            Some(Self::SevenZip)
+        } else if prefix.starts_with(GZIP_SIGNATURE) {
+            let decoder = GzDecoder::new(BufReader::new(File::open(path).ok()?));
+            is_tar(&read_prefix(decoder)?).then_some(Self::TarGz)
+        } else if prefix.starts_with(XZ_SIGNATURE) {
+            let decoder = XzReader::new(BufReader::new(File::open(path).ok()?), true);
+            is_tar(&read_prefix(decoder)?).then_some(Self::TarXz)
+        } else {
+            is_tar(&prefix).then_some(Self::Tar)
+        }
+    }
+}
+
+/// Read the bytes needed for detecting the format from the start of the reader.
+fn read_prefix<R: Read>(reader: R) -> Option<Vec<u8>> {
+    let mut prefix = Vec::with_capacity(SIGNATURE_LENGTH);
+    reader
+        .take(SIGNATURE_LENGTH as u64)
+        .read_to_end(&mut prefix)
+        .ok()?;
+    Some(prefix)
+}
+
+/// Check for the POSIX tar magic in the first header block.
+fn is_tar(prefix: &[u8]) -> bool {
+    prefix.get(TAR_MAGIC_OFFSET..SIGNATURE_LENGTH) == Some(TAR_MAGIC)
+}
+
+impl ArchiveDisposition {
+    /// Handle the extracted archive according to the disposition.
+    pub fn apply(self, path: &Path) -> anyhow::Result<()> {
+        match self {
+            Self::Trash => trash::delete(path)
+                .with_context(|| format!("Failed to move archive to trash: {}", path.display())),
+            Self::Delete => std::fs::remove_file(path)
+                .with_context(|| format!("Failed to delete archive: {}", path.display())),
+            Self::Keep => Ok(()),
+        }
+    }
+}
+
+impl<R: Read> TarArchive<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            archive: tar::Archive::new(reader),
+        }
+    }
+}
+
+impl<R: Read> Archive for TarArchive<R> {
+    fn total_size(&self) -> Option<u64> {
+        None
+    }
+
+    fn for_each_entry(
+        &mut self,
+        visit: &mut dyn FnMut(&ArchiveEntry, &mut dyn Read) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        for entry in self
+            .archive
+            .entries()
+            .context("Failed to read tar archive")?
+        {
+            let mut entry = entry.context("Failed to read tar entry")?;
+            let is_dir = match entry.header().entry_type() {
+                tar::EntryType::Regular | tar::EntryType::Continuous => false,
+                tar::EntryType::Directory => true,
+                // Links and special files are not extracted
+                _ => continue,
+            };
+            let info = ArchiveEntry {
+                name: entry
+                    .path()
+                    .context("Failed to read tar entry path")?
+                    .to_string_lossy()
+                    .into_owned(),
+                is_dir,
+                size: entry.size(),
+                modified: entry
+                    .header()
+                    .mtime()
+                    .ok()
+                    .map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds)),
+                mode: entry.header().mode().ok(),
+            };
+            visit(&info, &mut entry)?;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> SevenZipArchive<R> {
+    pub fn new(reader: R) -> anyhow::Result<Self> {
+        let reader =
+            ArchiveReader::new(reader, Password::empty()).context("Failed to read 7z archive")?;
+        Ok(Self { reader })
+    }
+}
+
+impl<R: Read + Seek> Archive for SevenZipArchive<R> {
+    fn total_size(&self) -> Option<u64> {
+        Some(
+            self.reader
+                .archive()
+                .files
+                .iter()
+                .map(|file| file.size)
+                .sum(),
+        )
+    }
+
+    fn for_each_entry(
+        &mut self,
+        visit: &mut dyn FnMut(&ArchiveEntry, &mut dyn Read) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        // The 7z reader only accepts its own error type from the callback,
+        // so keep the original error and stop with a placeholder
+        let mut failure = None;
+        let result = self.reader.for_each_entries(|entry, data| {
+            if entry.is_anti_item {
+                return Ok(true);
+            }
+            match visit(&seven_zip_entry(entry), data) {
+                Ok(()) => Ok(true),
+                Err(error) => {
+                    failure = Some(error);
+                    Err(sevenz_rust2::Error::Other("Extraction stopped".into()))
+                }
+            }
+        });
+        if let Some(error) = failure {
+            return Err(error);
+        }
+        result.context("Failed to read 7z archive")
+    }
+
+    fn list_entries(&mut self) -> anyhow::Result<Vec<ArchiveEntry>> {
+        Ok(self
+            .reader
+            .archive()
+            .files
+            .iter()
+            .filter(|entry| !entry.is_anti_item)
+            .map(seven_zip_entry)
+            .collect())
+    }
+}
+
+/// Get the entry fields from a 7z archive entry.
+fn seven_zip_entry(entry: &sevenz_rust2::ArchiveEntry) -> ArchiveEntry {
+    ArchiveEntry {
+        name: entry.name.clone(),
+        is_dir: entry.is_directory,
+        size: entry.size,
+        modified: entry
+            .has_last_modified_date
+            .then(|| entry.last_modified_date.into()),
+        mode: (entry.has_windows_attributes
+            && entry.windows_attributes & SEVEN_ZIP_UNIX_EXTENSION != 0)
+            .then_some(entry.windows_attributes >> 16),
+    }
+}
+
+/// Open a tar or 7z archive for sequential reading.
+///
+/// For formats without a known total size,
+/// the progress bar tracks the compressed input instead of the extracted data.
+pub fn open_archive(
+    path: &Path,
+    format: ArchiveFormat,
+    progress_bar: &ProgressBar,
+) -> anyhow::Result<Box<dyn Archive>> {
+    let file =
+        File::open(path).with_context(|| format!("Failed to open archive: {}", path.display()))?;
+    if format == ArchiveFormat::SevenZip {
+        let archive = SevenZipArchive::new(BufReader::new(file))
+            .with_context(|| format!("Failed to read archive: {}", path.display()))?;
+        progress_bar.set_length(archive.total_size().unwrap_or_default());
+        return Ok(Box::new(archive));
+    }
+
+    progress_bar.set_length(file.metadata().map(|m| m.len()).unwrap_or_default());
+    let input = progress_bar.wrap_read(BufReader::new(file));
+    let archive: Box<dyn Archive> = match format {
+        ArchiveFormat::Tar => Box::new(TarArchive::new(input)),
+        ArchiveFormat::TarGz => Box::new(TarArchive::new(GzDecoder::new(input))),
+        ArchiveFormat::TarXz => Box::new(TarArchive::new(XzReader::new(input, true))),
+        ArchiveFormat::Zip | ArchiveFormat::SevenZip => {
+            anyhow::bail!("Not a sequential archive: {}", path.display())
+        }
+    };
+    Ok(archive)
+}
+
+/// Write the staged file entries of a tar or 7z archive to the staging directory.
+/// Entries are matched to the staged entries by name, in archive order for duplicate names.
+pub(crate) fn extract_entries(
+    archive: &mut dyn Archive,
+    entries: Vec<StagedEntry>,
+    archive_name: &str,
+    progress_bar: &ProgressBar,
+    options: &ExtractOptions,
+) -> anyhow::Result<()> {
+    let track_entry_progress = archive.total_size().is_some();
+    let budget = options.limits.size_budget(archive_name, &options.cancel);
+    let mut staged: HashMap<String, VecDeque<StagedEntry>> = HashMap::new();
+    for entry in entries {
+        staged
+            .entry(entry.entry.name.clone())
+            .or_default()
+            .push_back(entry);
+    }
+    archive.for_each_entry(&mut |entry, data| {
+        if entry.is_dir {
+            return Ok(());
+        }
+        let Some(staged) = staged.get_mut(&entry.name).and_then(VecDeque::pop_front) else {
+            return Ok(());
+        };
+        let mut output_file = entry::create_file(&staged)?;
+        let mut reader = budget.reader(data);
+        let copied = if track_entry_progress {
+            io::copy(&mut progress_bar.wrap_read(reader), &mut output_file)
+        } else {
+            io::copy(&mut reader, &mut output_file)
+        };
+        copied.map_err(|e| {
+            limits::copy_error(
+                e,
+                format!(
+                    "Failed to copy data to output file: {}",
+                    staged.output_path.display()
+                ),
+            )
+        })?;
+        entry::finish_file(&staged, &output_file)
+    })
+}
+
+/// Set the modification time and Unix permissions for an extracted file.
+pub(crate) fn set_file_metadata(
+    file: &File,
+    path: &Path,
+    modified: Option<SystemTime>,
+    mode: Option<u32>,
+) -> anyhow::Result<()> {
+    if let Some(modified) = modified {
+        file.set_modified(modified)
+            .with_context(|| format!("Failed to set modification time: {}", path.display()))?;
+    }
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(mode & 0o777))
+            .with_context(|| format!("Failed to set permissions: {}", path.display()))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use indicatif::{MultiProgress, ProgressDrawTarget};
+
+    use super::*;
+    use crate::report::ExtractedFiles;
+    use crate::test_utils::TempDir;
+
+    /// Write the archive to the directory and extract it next to it.
+    fn extract(dir: &Path, name: &str, data: &[u8]) -> ExtractedFiles {
+        let path = dir.join(name);
+        std::fs::write(&path, data).expect("write archive");
+        let options = ExtractOptions::default();
+        let plan = crate::plan_archive(&path, &options).expect("plan archive");
+        crate::extract_planned(
+            plan,
+            &MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+            &options,
+        )
+        .expect("extract archive")
+    }
+
+    fn create_tar(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o640);
+            header.set_mtime(1_600_000_000);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, name, *data)
+                .expect("append tar entry");
+        }
+        builder.into_inner().expect("finish tar")
+    }
+
+    #[test]
+    fn archive_format_from_extension() {
+        let format = |name: &str| ArchiveFormat::from_path(Path::new(name));
+        assert_eq!(format("album.zip"), Some(ArchiveFormat::Zip));
+        assert_eq!(format("ALBUM.ZIP"), Some(ArchiveFormat::Zip));
+        assert_eq!(format("album.tar"), Some(ArchiveFormat::Tar));
+        assert_eq!(format("album.tar.gz"), Some(ArchiveFormat::TarGz));
+        assert_eq!(format("album.tgz"), Some(ArchiveFormat::TarGz));
+        assert_eq!(format("album.Tar.XZ"), Some(ArchiveFormat::TarXz));
+        assert_eq!(format("album.7z"), Some(ArchiveFormat::SevenZip));
+        assert_eq!(format("album.gz"), None);
+        assert_eq!(format("track.flac"), None);
+    }
+
+    #[test]
+    fn detect_archive_by_signature() {
+        let dir = TempDir::new("detect");
+        let write = |name: &str, data: &[u8]| {
+            let path = dir.join(name);
+            std::fs::write(&path, data).expect("write file");
+            path
+        };
+
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        zip.start_file("a.flac", zip::write::SimpleFileOptions::default())
+            .expect("start file");
+        let zip = zip.finish().expect("finish zip").into_inner();
+        let tar = create_tar(&[("a.flac", b"data")]);
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        io::Write::write_all(&mut gzip, &tar).expect("compress tar");
+        let tar_gz = gzip.finish().expect("finish gzip");
+        let mut plain_gzip =
+            flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        io::Write::write_all(&mut plain_gzip, b"not a tar").expect("compress data");
+        let plain_gzip = plain_gzip.finish().expect("finish gzip");
+
+        let detect = |path: PathBuf| ArchiveFormat::detect(&path);
+        assert_eq!(detect(write("ALBUM.ZIP", &zip)), Some(ArchiveFormat::Zip));
+        assert_eq!(detect(write("album", &zip)), Some(ArchiveFormat::Zip));
+        assert_eq!(detect(write("album", &tar)), Some(ArchiveFormat::Tar));
+        assert_eq!(detect(write("album.flac", &tar)), None);
+        assert_eq!(
+            detect(write("album.gz", &tar_gz)),
+            Some(ArchiveFormat::TarGz)
+        );
+        assert_eq!(
+            detect(write("album.7z", b"7z\xbc\xaf\x27\x1c\x00\x04")),
+            Some(ArchiveFormat::SevenZip)
+        );
+        assert_eq!(detect(write("notes.gz", &plain_gzip)), None);
+        assert_eq!(detect(write("track.zip", b"fLaC")), None);
+        // Zip-based documents are not archives to extract
+        assert_eq!(detect(write("Liner Notes.docx", &zip)), None);
+        assert_eq!(detect(write("Lyrics.epub", &zip)), None);
+        assert_eq!(detect(dir.join("missing.zip")), None);
+
+        // Downloads are sniffed whatever their extension
+        assert_eq!(
+            ArchiveFormat::sniff(&dir.join("album.flac")),
+            Some(ArchiveFormat::Tar)
+        );
+        assert_eq!(
+            ArchiveFormat::sniff(&dir.join("Lyrics.epub")),
+            Some(ArchiveFormat::Zip)
+        );
+        assert_eq!(ArchiveFormat::sniff(&dir.join("track.zip")), None);
+    }
+
+    #[test]
+    fn archive_disposition() {
+        let dir = TempDir::new("disposition");
+        let archive = dir.join("album.zip");
+        std::fs::write(&archive, b"PK").expect("write archive");
+        ArchiveDisposition::Keep.apply(&archive).expect("keep");
+        assert!(archive.exists());
+        ArchiveDisposition::Delete.apply(&archive).expect("delete");
+        assert!(!archive.exists());
+        assert!(ArchiveDisposition::Delete.apply(&archive).is_err());
+    }
+
+    #[test]
+    fn extract_tar_entries() {
+        let dir = TempDir::new("tar");
+        let data = create_tar(&[("Album/01 Track.aiff", b"first"), ("a:b.flac", b"second")]);
+        let written = extract(&dir, "album.tar", &data);
+
+        assert_eq!(written.files.len(), 2);
+        let track = dir.join("Album").join("01 Track.aif");
+        assert_eq!(std::fs::read(&track).expect("read"), b"first");
+        assert_eq!(
+            std::fs::read(dir.join("a_b.flac")).expect("read"),
+            b"second"
+        );
+        let modified = std::fs::metadata(&track)
+            .and_then(|m| m.modified())
+            .expect("modified time");
+        assert_eq!(modified, UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+    }
+
+    #[test]
+    fn extract_seven_zip_entries() {
+        let dir = TempDir::new("7z");
+        let mut writer =
+            sevenz_rust2::ArchiveWriter::new(io::Cursor::new(Vec::new())).expect("create 7z");
+        writer
+            .push_archive_entry(
+                sevenz_rust2::ArchiveEntry::new_file("Album/01 Track.flac"),
+                Some(b"track data".as_slice()),
+            )
+            .expect("add 7z entry");
+        let data = writer.finish().expect("finish 7z").into_inner();
+
+        let mut archive = SevenZipArchive::new(io::Cursor::new(&data)).expect("open 7z");
+        assert_eq!(archive.total_size(), Some(10));
+        assert_eq!(archive.list_entries().expect("list entries").len(), 1);
+        extract(&dir, "album.7z", &data);
+        assert_eq!(
+            std::fs::read(dir.join("Album").join("01 Track.flac")).expect("read"),
+            b"track data"
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use colored::Colorize;
+use lofty::config::WriteOptions;
+use lofty::file::{AudioFile, TaggedFileExt};
+use lofty::picture::{Picture, PictureType};
+use lofty::tag::Tag;
+
+use crate::utils;
+
+/// Audio file extensions that support embedded pictures.
+const TAGGABLE_EXTENSIONS: [&str; 5] = ["aif", "aiff", "flac", "m4a", "mp3"];
+
+/// Image file extensions that can be used as cover art.
+const IMAGE_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+
+/// Result of embedding cover art into the files of a single archive.
+#[derive(Debug, Default)]
+pub struct EmbedResult {
+    /// Audio files that got the cover embedded
+    pub updated: Vec<PathBuf>,
+    /// Audio files that could not be updated, or the cover image if it could not be read,
+    /// with the error
+    pub failed: Vec<(PathBuf, anyhow::Error)>,
+}
+
+/// Embed the cover image from the given files into the audio files among them
+/// that do not already have a front cover picture.
+///
+/// Pass the files extracted from a single archive,
+/// so every album gets its own cover even when albums share a directory.
+/// Files that fail to update are returned in the result and skipped,
+/// and so is the cover image if it can not be read.
+#[must_use]
+pub fn embed_cover_art(files: &[PathBuf]) -> EmbedResult {
+    let mut images = Vec::new();
+    let mut audio_files = Vec::new();
+    for path in files {
+        if !path.is_file() {
+            continue;
+        }
+        let Some(extension) = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_lowercase)
+        else {
+            continue;
+        };
+        if IMAGE_EXTENSIONS.contains(&extension.as_str()) {
+            images.push(path.clone());
+        } else if TAGGABLE_EXTENSIONS.contains(&extension.as_str()) {
+            audio_files.push(path.clone());
+        }
+    }
+
+    let mut result = EmbedResult::default();
+    if audio_files.is_empty() {
+        return result;
+    }
+    let Some(cover_path) = find_cover_image(images) else {
+        return result;
+    };
+    let cover = match read_cover(&cover_path) {
+        Ok(cover) => cover,
+        Err(e) => {
+            result.failed.push((cover_path, e));
+            return result;
+        }
+    };
+
+    audio_files.sort();
+    for file in audio_files {
+        match embed_picture(&file, &cover) {
+            Ok(true) => result.updated.push(file),
+            Ok(false) => {}
+            Err(e) => result.failed.push((file, e)),
+        }
+    }
+    result
+}
+
+/// Read the image file as a front cover picture.
+fn read_cover(path: &Path) -> anyhow::Result<Picture> {
+    let data = fs::read(path)
+        .with_context(|| format!("Failed to read cover image: {}", path.display()))?;
+    let mut cover = Picture::from_reader(&mut data.as_slice())
+        .with_context(|| format!("Unsupported cover image: {}", path.display()))?;
+    cover.set_pic_type(PictureType::CoverFront);
+    Ok(cover)
+}
+
+/// Print the files that failed to update, and the updated files with the verbose flag.
+pub fn print_embedded(result: &EmbedResult, verbose: bool) {
+    for (_, error) in &result.failed {
+        eprintln!("{}", format!("Error: {error:#}").red());
+    }
+    if !result.updated.is_empty() && verbose {
+        println!("Embedded cover art ({}):", result.updated.len());
+        for file in &result.updated {
+            println!(
+                "  {}",
+                utils::get_relative_path_from_current_working_directory(file).display()
+            );
+        }
+    }
+}
+
+/// Pick the cover image from the given images.
+/// Prefers a file named "cover", otherwise uses the first image in alphabetical order.
+fn find_cover_image(mut images: Vec<PathBuf>) -> Option<PathBuf> {
+    images.sort();
+    let cover = images.iter().position(|image| {
+        image
+            .file_stem()
+            .is_some_and(|stem| stem.eq_ignore_ascii_case("cover"))
+    });
+    match cover {
+        Some(index) => Some(images.swap_remove(index)),
+        None => images.into_iter().next(),
+    }
+}
+
+/// Add the picture to the audio file unless it already has a front cover.
+/// Returns true if the file was modified.
+fn embed_picture(path: &Path, cover: &Picture) -> anyhow::Result<bool> {
+    let mut tagged_file = lofty::read_from_path(path)
+        .with_context(|| format!("Failed to read tags: {}", path.display()))?;
+
+    let has_front_cover = tagged_file
+        .tags()
+        .iter()
+        .any(|tag| tag.get_picture_type(PictureType::CoverFront).is_some());
+    if has_front_cover {
+        return Ok(false);
+    }
+
+    let tag_type = tagged_file.primary_tag_type();
+    if tagged_file.primary_tag().is_none() {
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+    let tag = tagged_file
+        .primary_tag_mut()
+        .context("Failed to create primary tag")?;
+    tag.push_picture(cover.clone());
+
+    tagged_file
+        .save_to_path(path, WriteOptions::default())
+        .with_context(|| format!("Failed to write cover art: {}", path.display()))?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    /// Smallest PNG signature that lofty recognizes as a picture.
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0\0\x01\0\0\0\x01\x08\x02\0\0\0";
+
+    /// FLAC file with only a stream info block.
+    fn flac() -> Vec<u8> {
+        let mut data = b"fLaC".to_vec();
+        // Last metadata block, stream info, 34 bytes
+        data.extend([0x80, 0, 0, 34]);
+        data.extend(4096_u16.to_be_bytes());
+        data.extend(4096_u16.to_be_bytes());
+        data.extend([0; 6]);
+        // Sample rate 44100, 2 channels, 16 bits per sample, no samples
+        data.extend(((44_100_u64 << 44) | (1 << 41) | (15 << 36)).to_be_bytes());
+        data.extend([0; 16]);
+        data
+    }
+
+    /// MP3 file with a few silent MPEG-1 layer III frames.
+    fn mp3() -> Vec<u8> {
+        let mut frame = vec![0xff, 0xfb, 0x90, 0x00];
+        frame.resize(417, 0);
+        frame.repeat(4)
+    }
+
+    fn picture_count(path: &Path) -> usize {
+        lofty::read_from_path(path)
+            .expect("read tags")
+            .tags()
+            .iter()
+            .map(|tag| tag.pictures().len())
+            .sum()
+    }
+
+    #[test]
+    fn embed_cover_per_archive() {
+        let dir = TempDir::new("artwork");
+        let write = |name: &str, data: &[u8]| {
+            let path = dir.join(name);
+            fs::write(&path, data).expect("write file");
+            path
+        };
+        let album = [
+            write("Artist - Album - 01 One.flac", &flac()),
+            write("Artist - Album - 02 Two.mp3", &mp3()),
+            write("cover.png", PNG),
+        ];
+        let other_album = [write("Artist - Other - 01 Three.flac", &flac())];
+
+        let result = embed_cover_art(&album);
+        assert!(result.failed.is_empty(), "{:?}", result.failed);
+        assert_eq!(result.updated, album[..2]);
+        assert_eq!(picture_count(&album[0]), 1);
+        assert_eq!(picture_count(&album[1]), 1);
+
+        let result = embed_cover_art(&album);
+        assert!(result.updated.is_empty());
+        let result = embed_cover_art(&other_album);
+        assert!(result.updated.is_empty());
+        assert_eq!(picture_count(&other_album[0]), 0);
+    }
+
+    #[test]
+    fn corrupt_cover_is_reported() {
+        let dir = TempDir::new("artwork-corrupt");
+        let track = dir.join("Artist - Album - 01 One.flac");
+        let cover = dir.join("cover.jpg");
+        fs::write(&track, flac()).expect("write track");
+        fs::write(&cover, b"not an image").expect("write cover");
+
+        let result = embed_cover_art(&[track.clone(), cover.clone()]);
+        assert!(result.updated.is_empty());
+        assert_eq!(result.failed.len(), 1);
+        assert_eq!(result.failed[0].0, cover);
+        assert_eq!(picture_count(&track), 0);
+    }
+
+    #[test]
+    fn cover_image_preference() {
+        let paths = |names: &[&str]| names.iter().map(PathBuf::from).collect::<Vec<_>>();
+        assert_eq!(
+            find_cover_image(paths(&["b.jpg", "Cover.PNG", "a.jpg"])),
+            Some(PathBuf::from("Cover.PNG"))
+        );
+        assert_eq!(
+            find_cover_image(paths(&["b.jpg", "a.png"])),
+            Some(PathBuf::from("a.png"))
+        );
+        assert_eq!(find_cover_image(Vec::new()), None);
+    }
+}
@@ -0,0 +1,181 @@
+use std::path::Path;
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+use crate::archive::ArchiveFormat;
+use crate::classify::{self, EntryKind};
+
+/// Regex to match an album track with the artist, album, track number and title,
+/// with an optional disc number like `1-01`.
+/// The shortest artist and album are used,
+/// so an album like `10 Years` is not taken for a track number,
+/// and a title like `Song - 12 Inch Mix` keeps its own separator.
+static RE_ALBUM_TRACK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"^(?<artist>.+?) - (?<album>.+?) - (?:(?<disc>\d{1,2})-)?(?<track>\d{2,3}) (?<title>.+)$",
+    )
+    .expect("Album track regex failed")
+});
+
+/// Regex to match a track number and title after the artist, for tracks without an album.
+static RE_TRACK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(?<artist>.+?) - (?:(?<disc>\d{1,2})-)?(?<track>\d{2,3}) (?<title>.+)$")
+        .expect("Track regex failed")
+});
+
+/// Fields parsed from a Bandcamp filename.
+///
+/// Bandcamp names album archives `Artist - Album.zip`,
+/// album tracks `Artist - Album - 01 Title.ext`, and single tracks `Artist - Title.ext`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParsedName {
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub disc: Option<u32>,
+    pub track: Option<u32>,
+    pub title: Option<String>,
+    /// Lowercase file extension, including the `tar` part for compressed tar archives
+    pub extension: Option<String>,
+}
+
+/// Parse the artist, album, disc, track number and title from a Bandcamp filename.
+///
+/// Artist names rarely contain the ` - ` separator,
+/// so the first separator splits the artist from the album or title,
+/// and any later separators are kept in the album or title.
+/// Fields that can not be found are `None`.
+#[must_use]
+pub fn parse_filename(filename: &str) -> ParsedName {
+    let (stem, extension) = split_extension(filename);
+    let stem = stem.trim();
+    let mut parsed = ParsedName {
+        extension,
+        ..ParsedName::default()
+    };
+    if stem.is_empty() {
+        return parsed;
+    }
+
+    if ArchiveFormat::from_path(Path::new(filename)).is_some() {
+        match split_artist(stem) {
+            (Some(artist), album) => {
+                parsed.artist = Some(artist);
+                parsed.album = Some(album);
+            }
+            (None, album) => parsed.album = Some(album),
+        }
+        return parsed;
+    }
+
+    let is_audio = classify::classify_by_extension(filename) == Some(EntryKind::Audio);
+    let captures = RE_ALBUM_TRACK
+        .captures(stem)
+        .or_else(|| RE_TRACK.captures(stem))
+        .filter(|_| is_audio);
+    if let Some(captures) = captures {
+        parsed.artist = Some(captures["artist"].trim().to_string());
+        parsed.album = captures
+            .name("album")
+            .map(|album| album.as_str().trim().to_string());
+        parsed.disc = captures
+            .name("disc")
+            .and_then(|disc| disc.as_str().parse().ok());
+        parsed.track = captures["track"].parse().ok();
+        parsed.title = Some(captures["title"].trim().to_string());
+        return parsed;
+    }
+
+    let (artist, title) = split_artist(stem);
+    parsed.artist = artist;
+    parsed.title = Some(title);
+    parsed
+}
+
+/// Split the name at the first separator.
+/// Returns the artist if there is one, and the rest of the name.
+fn split_artist(name: &str) -> (Option<String>, String) {
+    match name.split_once(" - ") {
+        Some((artist, rest)) if !artist.trim().is_empty() && !rest.trim().is_empty() => {
+            (Some(artist.trim().to_string()), rest.trim().to_string())
+        }
+        _ => (None, name.trim().to_string()),
+    }
+}
+
+/// Split the filename to the stem and the lowercase extension.
+fn split_extension(filename: &str) -> (&str, Option<String>) {
+    let lowercase = filename.to_lowercase();
+    for extension in ["tar.gz", "tar.xz", "tgz", "txz"] {
+        if lowercase.len() > extension.len() + 1 && lowercase.ends_with(&format!(".{extension}")) {
+            let stem = &filename[..filename.len() - extension.len() - 1];
+            return (stem, Some(extension.to_string()));
+        }
+    }
+    match filename.rsplit_once('.') {
+        Some((stem, extension))
+            if !stem.is_empty()
+                && !extension.is_empty()
+                && !extension.contains(' ')
+                && extension.len() <= 5 =>
+        {
+            (stem, Some(extension.to_lowercase()))
+        }
+        _ => (filename, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Filenames with the expected fields, separated by tabs, with `-` for missing fields.
+    const FIXTURES: &str = include_str!("../tests/fixtures/bandcamp_filenames.tsv");
+
+    fn field(value: &str) -> Option<String> {
+        (value != "-").then(|| value.to_string())
+    }
+
+    fn number(value: &str) -> Option<u32> {
+        (value != "-").then(|| value.parse().expect("fixture number"))
+    }
+
+    #[test]
+    fn parse_fixture_filenames() {
+        let mut count = 0;
+        for (line_number, line) in FIXTURES.lines().enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let columns: Vec<&str> = line.split('\t').collect();
+            assert_eq!(columns.len(), 7, "fixture line {}: {line}", line_number + 1);
+            let expected = ParsedName {
+                artist: field(columns[1]),
+                album: field(columns[2]),
+                disc: number(columns[3]),
+                track: number(columns[4]),
+                title: field(columns[5]),
+                extension: field(columns[6]),
+            };
+            assert_eq!(
+                parse_filename(columns[0]),
+                expected,
+                "fixture line {}: {}",
+                line_number + 1,
+                columns[0]
+            );
+            count += 1;
+        }
+        assert!(count >= 30);
+    }
+
+    #[test]
+    fn extensions() {
+        assert_eq!(
+            split_extension("Artist - Album.TAR.GZ"),
+            ("Artist - Album", Some("tar.gz".to_string()))
+        );
+        assert_eq!(split_extension("Mr. Bungle"), ("Mr. Bungle", None));
+        assert_eq!(split_extension("README"), ("README", None));
+    }
+}
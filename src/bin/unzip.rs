@@ -1,38 +1,140 @@
-use std::ffi::OsStr;
-use std::ffi::OsString;
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::sync::LazyLock;
+use std::process::ExitCode;
 
 use anyhow::Result;
 use clap::Parser;
 use colored::Colorize;
 
-static ZIP_EXTENSION: LazyLock<Option<OsString>> = LazyLock::new(|| Some(OsString::from("zip")));
+use bandcamp_dl::archive::{ArchiveDisposition, ArchiveFormat};
+use bandcamp_dl::artwork;
+use bandcamp_dl::collision::{self, NameRegistry};
+use bandcamp_dl::config;
+use bandcamp_dl::events::EventSink;
+use bandcamp_dl::filter::{self, EntryFilter};
+use bandcamp_dl::limits::ExtractLimits;
+use bandcamp_dl::metadata::Metadata;
+use bandcamp_dl::rename::{self, RenameTemplate, Renamer};
+use bandcamp_dl::report::{self, ArchiveReport, FileCounts, RunStatus};
+use bandcamp_dl::staging;
+use bandcamp_dl::utils::{self, FilenameOptions, SanitizeProfile, UnicodeForm};
+use bandcamp_dl::{CancelToken, Cancelled, ExtractOptions};
 
 #[derive(Parser)]
-#[command(author, about = "Extract all zip files concurrently", version)]
+#[command(
+    author,
+    about = "Extract all zip, tar and 7z archives concurrently",
+    version
+)]
 struct Args {
     /// Optional input path
     input: Option<String>,
 
+    /// Embed cover image into audio files before removing images
+    #[arg(short, long)]
+    embed_cover: bool,
+
+    /// Exclude archive entries matching the glob pattern, can be given multiple times
+    #[arg(short = 'x', long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Move booklets, videos and other non-audio extras to this directory,
+    /// relative to the extraction directory, or an absolute path for a central directory
+    #[arg(long, value_name = "DIR")]
+    extras: Option<PathBuf>,
+
+    /// Stop all extractions after the first failure
+    #[arg(long)]
+    fail_fast: bool,
+
     /// Overwrite existing files
     #[arg(short, long)]
     force: bool,
 
-    /// Get zip files recursively
+    /// Only extract archive entries matching the glob pattern, can be given multiple times
+    #[arg(short, long, value_name = "PATTERN")]
+    include: Vec<String>,
+
+    /// Number of parallel workers per archive for large archives
+    #[arg(short, long, default_value_t = 1, value_name = "COUNT")]
+    jobs: usize,
+
+    /// Maximum nesting depth of archive entry paths, 0 for no limit
+    #[arg(long, value_name = "DEPTH")]
+    max_depth: Option<usize>,
+
+    /// Maximum number of entries per archive, 0 for no limit
+    #[arg(long, value_name = "COUNT")]
+    max_entries: Option<usize>,
+
+    /// Maximum compression ratio per archive, 0 for no limit
+    #[arg(long, value_name = "RATIO")]
+    max_ratio: Option<u64>,
+
+    /// Maximum total uncompressed size per archive, for example 20G, 0 for no limit
+    #[arg(long, value_name = "SIZE", value_parser = utils::parse_size)]
+    max_size: Option<u64>,
+
+    /// What to do with archives inside archives after extracting them
+    #[arg(long, value_enum, default_value_t, value_name = "ACTION")]
+    nested_archives: ArchiveDisposition,
+
+    /// Maximum depth for extracting archives inside archives, 0 to keep them as they are
+    #[arg(long, default_value_t = 0, value_name = "DEPTH")]
+    nested_depth: usize,
+
+    /// Unicode normalization form for filenames
+    #[arg(short, long, value_enum, value_name = "FORM")]
+    normalize: Option<UnicodeForm>,
+
+    /// Use option defaults from this profile in the config file
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Only print errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Get archives recursively
     #[arg(short, long)]
     recursive: bool,
 
+    /// Rename audio files with a filename template, keeping the extension,
+    /// for example "{artist} - {title}". Has the same fields as the bcdl output template
+    #[arg(long, value_name = "TEMPLATE")]
+    rename: Option<RenameTemplate>,
+
+    /// Print the new filenames without renaming the files
+    #[arg(long, requires = "rename")]
+    rename_preview: bool,
+
+    /// Filename sanitization profile
+    #[arg(short, long, value_enum, default_value_t, value_name = "PROFILE")]
+    sanitize: SanitizeProfile,
+
+    /// Transliterate non-ASCII filenames to ASCII
+    #[arg(short, long)]
+    transliterate: bool,
+
+    /// Revert the renames from a rename log and exit
+    #[arg(long, value_name = "LOG", conflicts_with = "rename")]
+    undo_rename: Option<PathBuf>,
+
     /// Verbose output
     #[arg(short, long)]
     verbose: bool,
 }
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
-    let input_path = bandcamp_dl::utils::resolve_path(args.input)?;
+async fn main() -> anyhow::Result<ExitCode> {
+    let args: Args = config::parse_args()?;
+    let quiet = args.quiet;
+    if let Some(log) = &args.undo_rename {
+        let result = rename::undo_renames(log)?;
+        rename::print_undo(&result);
+        return Ok(RunStatus::from_counts(result.reverted.len(), result.skipped.len()).into());
+    }
+    let input_path = bandcamp_dl::utils::resolve_path(args.input.clone())?;
 
     if args.verbose {
         println!(
@@ -43,28 +145,138 @@ async fn main() -> anyhow::Result<()> {
     }
 
     let zip_files = gather_zip_files(&input_path, args.recursive)?;
-    if zip_files.is_empty() {
-        println!("No zip files found");
-        return Ok(());
+    remove_leftovers(zip_files.iter().filter_map(|zip| zip.parent()), quiet);
+    match zip_files.len() {
+        0 => {
+            if !quiet {
+                println!("No archives found");
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        _ if quiet => {}
+        1 => println!("Extracting 1 archive"),
+        count => println!("Extracting {count} archives"),
     }
 
-    if zip_files.len() > 1 {
-        println!("Extracting {} zip files", zip_files.len());
-    } else {
-        println!("Extracting 1 zip file");
+    let filenames = FilenameOptions {
+        sanitize: args.sanitize,
+        transliterate: args.transliterate,
+        normalize: args.normalize,
+    };
+    let names = NameRegistry::new();
+    let options = extract_options(&args, filenames, &names)?;
+    let reports = bandcamp_dl::extract_archives(zip_files, &options).await;
+    for report in &reports {
+        match &report.result {
+            Ok(extracted) => extracted.print_nested_errors(),
+            Err(e) if !e.is::<Cancelled>() => eprintln!("{}", format!("Error: {e:#}").red()),
+            Err(_) => {}
+        }
+    }
+    let extracted_files = reports
+        .iter()
+        .filter_map(|report| report.result.as_ref().ok())
+        .flat_map(|extracted| &extracted.files);
+    remove_leftovers(extracted_files.filter_map(|file| file.parent()), quiet);
+    if args.embed_cover {
+        for extracted in reports
+            .iter()
+            .filter_map(|report| report.result.as_ref().ok())
+        {
+            let result = artwork::embed_cover_art(&extracted.files);
+            artwork::print_embedded(&result, args.verbose);
+        }
     }
 
-    let extracted_file_count = bandcamp_dl::extract_zip_files(zip_files, args.force).await;
-    let removed_image_count = bandcamp_dl::utils::remove_images(&input_path, args.verbose)?;
+    let removed_images = bandcamp_dl::utils::remove_images(&input_path, args.verbose)?;
+    if let Some(template) = &args.rename {
+        rename_files(&reports, template, &input_path, filenames, &args)?;
+    }
+    if !quiet {
+        collision::print_collisions(&names.collisions());
+        filter::print_skipped(&options.filter);
 
-    if args.verbose {
-        // Count files actually unpacked from the zips,
-        // minus the cover images removed afterwards,
-        // rather than diffing the directory contents.
-        let added_files = extracted_file_count.saturating_sub(removed_image_count);
-        println!("{}", format!("Added {added_files} new files").green());
+        let rows: Vec<(String, FileCounts)> = reports
+            .iter()
+            .map(|report| {
+                let name = utils::get_relative_path_from_current_working_directory(&report.archive);
+                (name.display().to_string(), report.counts(&removed_images))
+            })
+            .collect();
+        report::print_summary(&rows, args.verbose);
     }
 
+    let failed = reports
+        .iter()
+        .filter(|report| report.result.is_err())
+        .count();
+    Ok(RunStatus::from_counts(reports.len() - failed, failed).into())
+}
+
+/// Remove leftover staging directories of interrupted runs from the directories.
+fn remove_leftovers<'a>(dirs: impl IntoIterator<Item = &'a Path>, quiet: bool) {
+    let removed = staging::remove_leftovers_in(dirs);
+    if !quiet {
+        staging::print_removed_leftovers(&removed);
+    }
+}
+
+/// Get archive extraction options from the arguments.
+fn extract_options(
+    args: &Args,
+    filenames: FilenameOptions,
+    names: &NameRegistry,
+) -> anyhow::Result<ExtractOptions> {
+    Ok(ExtractOptions {
+        overwrite: args.force,
+        filenames,
+        names: names.clone(),
+        entry_jobs: args.jobs,
+        limits: ExtractLimits::from_options(
+            args.max_size,
+            args.max_ratio,
+            args.max_entries,
+            args.max_depth,
+        ),
+        filter: EntryFilter::new(&args.include, &args.exclude)?,
+        extras_dir: args.extras.clone(),
+        nested_depth: args.nested_depth,
+        nested_disposition: args.nested_archives,
+        events: EventSink::default(),
+        fail_fast: args.fail_fast,
+        cancel: CancelToken::default(),
+    })
+}
+
+/// Rename the extracted audio files with the rename template,
+/// or only print the new names in preview mode.
+/// The renames are logged to a file in the input directory so they can be reverted.
+fn rename_files(
+    reports: &[ArchiveReport],
+    template: &RenameTemplate,
+    input_path: &Path,
+    filenames: FilenameOptions,
+    args: &Args,
+) -> Result<()> {
+    let mut planner = Renamer::new(template.clone(), filenames);
+    let mut renames = Vec::new();
+    for report in reports {
+        if let Ok(extracted) = &report.result {
+            let archive = utils::get_filename_from_path(&report.archive)?;
+            renames.extend(planner.plan(&extracted.files, &Metadata::from_filename(&archive)));
+        }
+    }
+    if args.rename_preview {
+        rename::print_renames(&renames, true);
+        return Ok(());
+    }
+    let log = rename::new_log_path(input_path);
+    let result = rename::apply_renames(&renames, &log)?;
+    rename::print_failed_renames(&result.failed);
+    if !result.renamed.is_empty() && !args.quiet {
+        rename::print_renames(&result.renamed, false);
+        rename::print_undo_command(&log);
+    }
     Ok(())
 }
 
@@ -76,7 +288,7 @@ fn gather_zip_files(input_path: &PathBuf, recursive: bool) -> Result<Vec<PathBuf
             .filter_map(|entry| {
                 let entry = entry.ok()?;
                 let path = entry.path();
-                if path.extension().map(OsStr::to_ascii_lowercase) == *ZIP_EXTENSION {
+                if ArchiveFormat::detect(&path).is_some() {
                     Some(path)
                 } else {
                     None
@@ -94,7 +306,7 @@ fn gather_zip_files_recursive(dir: &Path) -> Result<Vec<PathBuf>> {
             let path = entry.path();
             if path.is_dir() {
                 zip_files.extend(gather_zip_files_recursive(&path)?);
-            } else if path.extension().map(OsStr::to_ascii_lowercase) == *ZIP_EXTENSION {
+            } else if ArchiveFormat::detect(&path).is_some() {
                 zip_files.push(path);
             }
         }
@@ -0,0 +1,152 @@
+use std::path::Path;
+
+/// Number of bytes from the start of a file needed to detect its content type.
+pub const CONTENT_PREFIX_LENGTH: usize = 12;
+
+const AUDIO_EXTENSIONS: [&str; 12] = [
+    "aac", "aif", "aifc", "aiff", "alac", "flac", "m4a", "mp3", "ogg", "opus", "wav", "wv",
+];
+
+const ARTWORK_EXTENSIONS: [&str; 6] = ["bmp", "gif", "jpeg", "jpg", "png", "webp"];
+
+const ARCHIVE_EXTENSIONS: [&str; 8] = ["7z", "gz", "rar", "tar", "tgz", "txz", "xz", "zip"];
+
+const EXTRA_EXTENSIONS: [&str; 14] = [
+    "cue", "doc", "docx", "htm", "html", "log", "m4v", "md", "mkv", "mov", "mp4", "nfo", "pdf",
+    "txt",
+];
+
+/// Type of file inside an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    Audio,
+    Artwork,
+    /// Nested archives, which are kept next to the audio files to be extracted in place
+    Archive,
+    /// Booklets, videos, text files and everything else
+    Extra,
+}
+
+/// Classify an archive entry.
+/// Uses the file extension when it is known,
+/// and otherwise the start of the file content if available.
+#[must_use]
+pub fn classify(name: &str, prefix: Option<&[u8]>) -> EntryKind {
+    classify_by_extension(name)
+        .or_else(|| prefix.map(classify_by_content))
+        .unwrap_or(EntryKind::Extra)
+}
+
+/// Classify a file by its extension.
+/// Returns `None` for missing and unknown extensions.
+#[must_use]
+pub fn classify_by_extension(name: &str) -> Option<EntryKind> {
+    let extension = Path::new(name).extension()?.to_str()?.to_lowercase();
+    if AUDIO_EXTENSIONS.contains(&extension.as_str()) {
+        Some(EntryKind::Audio)
+    } else if ARTWORK_EXTENSIONS.contains(&extension.as_str()) {
+        Some(EntryKind::Artwork)
+    } else if ARCHIVE_EXTENSIONS.contains(&extension.as_str()) {
+        Some(EntryKind::Archive)
+    } else if EXTRA_EXTENSIONS.contains(&extension.as_str()) {
+        Some(EntryKind::Extra)
+    } else {
+        None
+    }
+}
+
+/// Classify a file from the signature at the start of its content.
+#[must_use]
+pub fn classify_by_content(prefix: &[u8]) -> EntryKind {
+    let at = |offset: usize, signature: &[u8]| {
+        prefix.get(offset..offset + signature.len()) == Some(signature)
+    };
+    let is_mpeg_frame = prefix.len() >= 2 && prefix[0] == 0xff && prefix[1] & 0xe0 == 0xe0;
+    if at(0, b"fLaC")
+        || at(0, b"ID3")
+        || at(0, b"OggS")
+        || (at(0, b"RIFF") && at(8, b"WAVE"))
+        || (at(0, b"FORM") && (at(8, b"AIFF") || at(8, b"AIFC")))
+        || (at(4, b"ftyp") && at(8, b"M4A"))
+        || is_mpeg_frame
+    {
+        EntryKind::Audio
+    } else if at(0, b"\xff\xd8\xff")
+        || at(0, b"\x89PNG")
+        || at(0, b"GIF8")
+        || (at(0, b"RIFF") && at(8, b"WEBP"))
+    {
+        EntryKind::Artwork
+    } else if at(0, b"PK\x03\x04")
+        || at(0, b"7z\xbc\xaf\x27\x1c")
+        || at(0, b"\x1f\x8b")
+        || at(0, b"\xfd7zXZ\x00")
+    {
+        EntryKind::Archive
+    } else {
+        EntryKind::Extra
+    }
+}
+
+/// Get the archive filename without the archive extensions,
+/// for example `Album` for `Album.tar.gz`.
+#[must_use]
+pub fn archive_stem(archive_name: &str) -> &str {
+    let path = Path::new(archive_name);
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or(archive_name);
+    let inner = Path::new(stem);
+    match inner.extension() {
+        Some(extension) if extension.eq_ignore_ascii_case("tar") => {
+            inner.file_stem().and_then(|s| s.to_str()).unwrap_or(stem)
+        }
+        _ => stem,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_known_extensions() {
+        assert_eq!(classify("Album/01 Track.FLAC", None), EntryKind::Audio);
+        assert_eq!(classify("02 Track.aif", None), EntryKind::Audio);
+        assert_eq!(classify("cover.jpg", None), EntryKind::Artwork);
+        assert_eq!(classify("Booklet.pdf", Some(b"fLaC")), EntryKind::Extra);
+        assert_eq!(classify("video.mp4", None), EntryKind::Extra);
+        assert_eq!(classify("Bonus Tracks.zip", None), EntryKind::Archive);
+        assert_eq!(classify("Stems.tar.gz", None), EntryKind::Archive);
+        assert_eq!(
+            classify("Booklet.docx", Some(b"PK\x03\x04")),
+            EntryKind::Extra
+        );
+    }
+
+    #[test]
+    fn classify_unknown_extensions_by_content() {
+        assert_eq!(classify("track", Some(b"fLaC\x00\x00")), EntryKind::Audio);
+        assert_eq!(
+            classify("track.bin", Some(b"RIFF\x24\x00\x00\x00WAVE")),
+            EntryKind::Audio
+        );
+        assert_eq!(
+            classify("track.dat", Some(b"\xff\xfb\x90\x00")),
+            EntryKind::Audio
+        );
+        assert_eq!(classify("folder", Some(b"\x89PNG\r\n")), EntryKind::Artwork);
+        assert_eq!(classify("bonus", Some(b"PK\x03\x04")), EntryKind::Archive);
+        assert_eq!(classify("README", Some(b"Liner notes")), EntryKind::Extra);
+        assert_eq!(classify("README", None), EntryKind::Extra);
+    }
+
+    #[test]
+    fn archive_stems() {
+        assert_eq!(archive_stem("Artist - Album.zip"), "Artist - Album");
+        assert_eq!(archive_stem("Album.tar.gz"), "Album");
+        assert_eq!(archive_stem("Album.TAR.XZ"), "Album");
+        assert_eq!(archive_stem("Album"), "Album");
+    }
+}
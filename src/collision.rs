@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use colored::Colorize;
+
+use crate::utils;
+
+/// An output path that was already claimed by another file during the same run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameCollision {
+    /// Original name of the file that was renamed
+    pub source: String,
+    /// Output path the file would have been written to
+    pub path: PathBuf,
+    /// Output path the file was written to instead
+    pub resolved: PathBuf,
+}
+
+/// Tracks output paths claimed during a run,
+/// to detect different files that map to the same path after sanitization.
+///
+/// The first claim of a path gets it unchanged, and later claims get a suffix.
+/// Claims made together with [`NameRegistry::claim_all`] are resolved in source order,
+/// and the callers make their claims in input order,
+/// so the same inputs always resolve to the same output paths.
+/// Paths are compared case-insensitively,
+/// since names that differ only in case collide on case-insensitive filesystems.
+/// Cloning shares the same registry.
+#[derive(Debug, Clone, Default)]
+pub struct NameRegistry {
+    state: Arc<Mutex<RegistryState>>,
+}
+
+#[derive(Debug, Default)]
+struct RegistryState {
+    /// Sources of the claimed paths by their collision keys
+    claimed: HashMap<String, String>,
+    collisions: Vec<NameCollision>,
+}
+
+impl NameRegistry {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Claim the output path for a file with the given source name.
+    ///
+    /// Returns the path unchanged if it is still free.
+    /// Otherwise, returns a path with a suffix derived from the source name.
+    #[must_use]
+    pub fn claim(&self, path: PathBuf, source: &str) -> PathBuf {
+        let mut state = self.state.lock().expect("Name registry lock poisoned");
+        state.claim(path, source)
+    }
+
+    /// Claim output paths for files with the given source names,
+    /// in the order of the source names, so the lowest source keeps a colliding path.
+    /// Returns the claimed paths in the order of the requests.
+    #[must_use]
+    pub fn claim_all(&self, requests: Vec<(PathBuf, String)>) -> Vec<PathBuf> {
+        let mut requests: Vec<_> = requests.into_iter().enumerate().collect();
+        requests.sort_by(|(_, (_, a)), (_, (_, b))| a.cmp(b));
+        let mut state = self.state.lock().expect("Name registry lock poisoned");
+        let mut claimed: Vec<_> = requests
+            .into_iter()
+            .map(|(index, (path, source))| (index, state.claim(path, &source)))
+            .collect();
+        claimed.sort_by_key(|(index, _)| *index);
+        claimed.into_iter().map(|(_, path)| path).collect()
+    }
+
+    /// Release the paths claimed for sources starting with the prefix,
+    /// together with their collisions, so an extraction that is started over can claim them again.
+    pub fn release(&self, source_prefix: &str) {
+        let mut state = self.state.lock().expect("Name registry lock poisoned");
+        state
+            .claimed
+            .retain(|_, source| !source.starts_with(source_prefix));
+        state
+            .collisions
+            .retain(|collision| !collision.source.starts_with(source_prefix));
+    }
+
+    /// Get all collisions detected so far.
+    #[must_use]
+    pub fn collisions(&self) -> Vec<NameCollision> {
+        self.state
+            .lock()
+            .expect("Name registry lock poisoned")
+            .collisions
+            .clone()
+    }
+}
+
+impl RegistryState {
+    fn claim(&mut self, path: PathBuf, source: &str) -> PathBuf {
+        if self.insert(&path, source) {
+            return path;
+        }
+
+        let hash = utils::fnv1a_hash(source);
+        let mut resolved = with_suffix(&path, &format!("~{hash:08x}"));
+        let mut counter = 2;
+        while !self.insert(&resolved, source) {
+            resolved = with_suffix(&path, &format!("~{hash:08x}-{counter}"));
+            counter += 1;
+        }
+        self.collisions.push(NameCollision {
+            source: source.to_string(),
+            path,
+            resolved: resolved.clone(),
+        });
+        resolved
+    }
+
+    /// Claim the path for the source if it is still free.
+    fn insert(&mut self, path: &Path, source: &str) -> bool {
+        match self.claimed.entry(collision_key(path)) {
+            Entry::Vacant(entry) => {
+                entry.insert(source.to_string());
+                true
+            }
+            Entry::Occupied(_) => false,
+        }
+    }
+}
+
+/// Print the files that were renamed because of a name collision.
+pub fn print_collisions(collisions: &[NameCollision]) {
+    if collisions.is_empty() {
+        return;
+    }
+    let heading = if collisions.len() == 1 {
+        "Renamed 1 file with a colliding name:".to_string()
+    } else {
+        format!("Renamed {} files with colliding names:", collisions.len())
+    };
+    println!("{}", heading.yellow());
+    for collision in collisions {
+        println!(
+            "  {} -> {}",
+            collision.source,
+            utils::get_relative_path_from_current_working_directory(&collision.resolved).display()
+        );
+    }
+}
+
+/// Case-insensitive comparison key for the path.
+fn collision_key(path: &Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+/// Add suffix to the end of the file stem, keeping the extension.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = path.extension().map_or_else(
+        || format!("{stem}{suffix}"),
+        |extension| format!("{stem}{suffix}.{}", extension.to_string_lossy()),
+    );
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_paths_are_unchanged() {
+        let registry = NameRegistry::new();
+        let path = PathBuf::from("/music/a_b.flac");
+        assert_eq!(registry.claim(path.clone(), "a:b.flac"), path);
+        assert!(registry.collisions().is_empty());
+    }
+
+    #[test]
+    fn colliding_paths_get_deterministic_suffix() {
+        let path = PathBuf::from("/music/a_b.flac");
+        let first = NameRegistry::new();
+        assert_eq!(first.claim(path.clone(), "a:b.flac"), path);
+        let resolved = first.claim(path.clone(), "a/b.flac");
+        assert_ne!(resolved, path);
+        assert_eq!(resolved.extension(), Some("flac".as_ref()));
+
+        let second = NameRegistry::new();
+        assert_eq!(second.claim(path.clone(), "a:b.flac"), path);
+        assert_eq!(second.claim(path.clone(), "a/b.flac"), resolved);
+
+        let collisions = first.collisions();
+        assert_eq!(collisions.len(), 1);
+        assert_eq!(collisions[0].source, "a/b.flac");
+        assert_eq!(collisions[0].path, path);
+        assert_eq!(collisions[0].resolved, resolved);
+    }
+
+    #[test]
+    fn paths_differing_in_case_collide() {
+        let registry = NameRegistry::new();
+        let cover = PathBuf::from("/music/Cover.jpg");
+        assert_eq!(registry.claim(cover.clone(), "Cover.jpg"), cover);
+        let resolved = registry.claim(PathBuf::from("/music/cover.JPG"), "cover.JPG");
+        assert_ne!(resolved, PathBuf::from("/music/cover.JPG"));
+        assert_eq!(registry.collisions().len(), 1);
+    }
+
+    #[test]
+    fn batch_claims_resolve_in_source_order() {
+        let path = PathBuf::from("/music/a_b.flac");
+        let requests = |sources: [&str; 2]| {
+            sources
+                .map(|source| (path.clone(), source.to_string()))
+                .to_vec()
+        };
+        let forward = NameRegistry::new().claim_all(requests(["b/a_b.flac", "a/a:b.flac"]));
+        let reverse = NameRegistry::new().claim_all(requests(["a/a:b.flac", "b/a_b.flac"]));
+        assert_eq!(forward[1], path);
+        assert_eq!(reverse[0], path);
+        assert_eq!(forward[0], reverse[1]);
+    }
+
+    #[test]
+    fn released_paths_can_be_claimed_again() {
+        let registry = NameRegistry::new();
+        let path = PathBuf::from("/music/a_b.flac");
+        assert_eq!(registry.claim(path.clone(), "Album.zip/a:b.flac"), path);
+        assert_ne!(registry.claim(path.clone(), "Album.zip/a_b.flac"), path);
+        registry.release("Album.zip/");
+        assert!(registry.collisions().is_empty());
+        assert_eq!(registry.claim(path.clone(), "Album.zip/a:b.flac"), path);
+    }
+
+    #[test]
+    fn identical_sources_get_unique_paths() {
+        let registry = NameRegistry::new();
+        let path = PathBuf::from("track.mp3");
+        let first = registry.claim(path.clone(), "track.mp3");
+        let second = registry.claim(path.clone(), "track.mp3");
+        let third = registry.claim(path, "track.mp3");
+        assert_ne!(first, second);
+        assert_ne!(second, third);
+        assert_ne!(first, third);
+    }
+}
@@ -0,0 +1,353 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgMatches, Command, CommandFactory, FromArgMatches};
+use toml::{Table, Value};
+
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Config file name in the current directory, overriding the user config.
+pub const LOCAL_CONFIG_FILE_NAME: &str = "bcdl.toml";
+
+/// Default option values from the config files.
+///
+/// Top-level keys are option names without the leading dashes, for example `output = "/music"`,
+/// and `[profile.<name>]` tables override them for a named profile.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    table: Table,
+}
+
+impl Config {
+    /// Load the user config file and the local config file from the current directory.
+    /// Values in the local config override the user config.
+    pub fn load() -> anyhow::Result<Self> {
+        let mut config = Self::default();
+        let local = std::env::current_dir()
+            .ok()
+            .map(|dir| dir.join(LOCAL_CONFIG_FILE_NAME));
+        for path in [user_config_path(), local].into_iter().flatten() {
+            if path.is_file() {
+                config.merge(Self::read(&path)?);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Read a single config file.
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+        content
+            .parse()
+            .with_context(|| format!("Failed to parse config file: {}", path.display()))
+    }
+
+    /// Override values with the values from another config.
+    pub fn merge(&mut self, other: Self) {
+        merge_tables(&mut self.table, other.table);
+    }
+
+    /// Get the option values for the profile, or the top-level values without a profile.
+    pub fn values(&self, profile: Option<&str>) -> anyhow::Result<Table> {
+        let mut values: Table = self
+            .table
+            .iter()
+            .filter(|(key, _)| key.as_str() != "profile")
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        if let Some(name) = profile {
+            let profile = self
+                .table
+                .get("profile")
+                .and_then(|profiles| profiles.get(name))
+                .and_then(Value::as_table)
+                .with_context(|| format!("Profile not found in config: {name}"))?;
+            merge_tables(&mut values, profile.clone());
+        }
+        Ok(values)
+    }
+
+    /// Convert the config values to command line arguments.
+    ///
+    /// Options given on the command line are left out so they take precedence,
+    /// together with options that conflict with them and the flags in `disabled`.
+    /// Options the command does not have are ignored,
+    /// so the same config can be used for all binaries.
+    pub fn to_args(
+        &self,
+        profile: Option<&str>,
+        command: &Command,
+        matches: &ArgMatches,
+        disabled: &[String],
+    ) -> anyhow::Result<Vec<OsString>> {
+        let given: Vec<&Arg> = command
+            .get_arguments()
+            .filter(|arg| {
+                matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+            })
+            .collect();
+        let mut args = Vec::new();
+        for (key, value) in self.values(profile)? {
+            let long = key.replace('_', "-");
+            let Some(arg) = command
+                .get_arguments()
+                .find(|arg| arg.get_long() == Some(long.as_str()))
+            else {
+                continue;
+            };
+            if disabled.contains(&long)
+                || given
+                    .iter()
+                    .any(|given| given.get_id() == arg.get_id() || conflicts(command, arg, given))
+            {
+                continue;
+            }
+            if !arg.get_action().takes_values() {
+                let enabled = value
+                    .as_bool()
+                    .with_context(|| format!("Config option must be true or false: {key}"))?;
+                if enabled {
+                    args.push(OsString::from(format!("--{long}")));
+                }
+                continue;
+            }
+            let values = match value {
+                Value::Array(values) => values,
+                value => vec![value],
+            };
+            for value in values {
+                let value = match value {
+                    Value::String(value) => value,
+                    Value::Integer(value) => value.to_string(),
+                    Value::Float(value) => value.to_string(),
+                    Value::Boolean(value) => value.to_string(),
+                    _ => anyhow::bail!("Unsupported value for config option: {key}"),
+                };
+                args.push(OsString::from(format!("--{long}={value}")));
+            }
+        }
+        Ok(args)
+    }
+}
+
+impl std::str::FromStr for Config {
+    type Err = toml::de::Error;
+
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            table: content.parse()?,
+        })
+    }
+}
+
+/// User config file location in the user config directory.
+#[must_use]
+pub fn user_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("bandcamp-dl").join(CONFIG_FILE_NAME))
+}
+
+/// Parse the command line arguments with defaults from the config files.
+///
+/// Config values are used for options not given on the command line,
+/// with the profile selected by the `--profile` argument.
+/// Flags enabled in the config can be turned off with `--no-<flag>`.
+/// Exits with a usage message if the arguments are invalid.
+pub fn parse_args<T: CommandFactory + FromArgMatches>() -> anyhow::Result<T> {
+    parse_args_from(std::env::args_os().collect(), &Config::load()?)
+}
+
+/// Parse the given command line arguments with defaults from the config.
+fn parse_args_from<T: CommandFactory + FromArgMatches>(
+    cli: Vec<OsString>,
+    config: &Config,
+) -> anyhow::Result<T> {
+    let command = T::command();
+    let (cli, disabled) = split_disabled_flags(cli, &command);
+    // Errors are reported by the second pass, which includes the config values
+    let matches = command.clone().ignore_errors(true).get_matches_from(&cli);
+    let profile = matches.get_one::<String>("profile").cloned();
+
+    let config_args = config.to_args(profile.as_deref(), &command, &matches, &disabled)?;
+    let args = cli
+        .first()
+        .cloned()
+        .into_iter()
+        .chain(config_args)
+        .chain(cli.into_iter().skip(1));
+    let matches = command.get_matches_from(args);
+    T::from_arg_matches(&matches).map_err(|error| error.exit())
+}
+
+/// Remove the `--no-<flag>` arguments for flags of the command.
+/// Returns the remaining arguments and the names of the disabled flags.
+fn split_disabled_flags(cli: Vec<OsString>, command: &Command) -> (Vec<OsString>, Vec<String>) {
+    let mut disabled = Vec::new();
+    let mut remaining = Vec::with_capacity(cli.len());
+    for (index, arg) in cli.into_iter().enumerate() {
+        let flag = arg
+            .to_str()
+            .and_then(|arg| arg.strip_prefix("--no-"))
+            .filter(|flag| index > 0 && is_flag(command, flag));
+        match flag {
+            Some(flag) => disabled.push(flag.to_string()),
+            None => remaining.push(arg),
+        }
+    }
+    (remaining, disabled)
+}
+
+/// Check if the command has a flag without values with the long name.
+fn is_flag(command: &Command, long: &str) -> bool {
+    command
+        .get_arguments()
+        .any(|arg| arg.get_long() == Some(long) && !arg.get_action().takes_values())
+}
+
+/// Check if the arguments conflict in either direction.
+fn conflicts(command: &Command, arg: &Arg, other: &Arg) -> bool {
+    let conflicts_with = |arg: &Arg, other: &Arg| {
+        command
+            .get_arg_conflicts_with(arg)
+            .iter()
+            .any(|conflict| conflict.get_id() == other.get_id())
+    };
+    conflicts_with(arg, other) || conflicts_with(other, arg)
+}
+
+/// Recursively merge the values from `other` into `table`.
+fn merge_tables(table: &mut Table, other: Table) {
+    for (key, value) in other {
+        match (table.get_mut(&key), value) {
+            (Some(Value::Table(existing)), Value::Table(value)) => merge_tables(existing, value),
+            (_, value) => {
+                table.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Args {
+        urls: String,
+        #[arg(short, long)]
+        force: bool,
+        #[arg(short, long)]
+        include: Vec<String>,
+        #[arg(short, long, default_value_t = 1)]
+        jobs: usize,
+        #[arg(short, long)]
+        output: Option<String>,
+        #[arg(long, requires = "output")]
+        preview: bool,
+        #[arg(long)]
+        profile: Option<String>,
+        #[arg(short, long, conflicts_with = "verbose")]
+        quiet: bool,
+        #[arg(short, long)]
+        verbose: bool,
+    }
+
+    const CONFIG: &str = r#"
+        force = true
+        include = ["*.flac", "*.pdf"]
+        jobs = 2
+        output = "/music"
+        history = "/data/history.db"
+
+        [profile.nas]
+        output = "/mnt/nas/music"
+        jobs = 8
+    "#;
+
+    fn config_args(config: &Config, cli: &[&str]) -> Vec<String> {
+        let command = Args::command();
+        let matches = command.clone().get_matches_from(cli);
+        let profile = matches.get_one::<String>("profile").cloned();
+        config
+            .to_args(profile.as_deref(), &command, &matches, &[])
+            .expect("config args")
+            .into_iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn config_to_args() {
+        let config: Config = CONFIG.parse().expect("parse config");
+        assert_eq!(
+            config_args(&config, &["bcdl", "url"]),
+            [
+                "--force",
+                "--include=*.flac",
+                "--include=*.pdf",
+                "--jobs=2",
+                "--output=/music"
+            ]
+        );
+        assert_eq!(
+            config_args(&config, &["bcdl", "url", "--profile", "nas", "-j", "4"]),
+            [
+                "--force",
+                "--include=*.flac",
+                "--include=*.pdf",
+                "--output=/mnt/nas/music"
+            ]
+        );
+
+        let command = Args::command();
+        let matches = command.clone().get_matches_from(["bcdl", "url"]);
+        assert!(
+            config
+                .to_args(Some("laptop"), &command, &matches, &[])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn command_line_overrides_config() {
+        let config: Config = "force = true\nverbose = true\noutput = \"/music\""
+            .parse()
+            .expect("parse config");
+        let parse = |cli: &[&str]| -> Args {
+            parse_args_from(cli.iter().map(OsString::from).collect(), &config).expect("parse args")
+        };
+
+        let args = parse(&["bcdl", "url"]);
+        assert!(args.force && args.verbose);
+
+        // Conflicting options from the config are left out
+        let args = parse(&["bcdl", "url", "--quiet"]);
+        assert!(args.quiet && !args.verbose);
+
+        // Flags from the config can be turned off
+        let args = parse(&["bcdl", "url", "--no-force"]);
+        assert!(!args.force);
+
+        // Requirements are met by the config values
+        let args = parse(&["bcdl", "url", "--preview"]);
+        assert_eq!(args.output.as_deref(), Some("/music"));
+    }
+
+    #[test]
+    fn local_config_overrides_user_config() {
+        let mut config: Config = CONFIG.parse().expect("parse config");
+        config.merge(
+            "force = false\n[profile.nas]\njobs = 16"
+                .parse()
+                .expect("parse config"),
+        );
+        let values = config.values(Some("nas")).expect("profile values");
+        assert_eq!(values["force"].as_bool(), Some(false));
+        assert_eq!(values["jobs"].as_integer(), Some(16));
+        assert_eq!(values["output"].as_str(), Some("/mnt/nas/music"));
+    }
+}
@@ -0,0 +1,236 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::ExtractOptions;
+use crate::archive::{self, ArchiveEntry};
+use crate::classify::EntryKind;
+use crate::staging::Staging;
+use crate::utils::{self, FilenameOptions};
+
+/// An archive entry staged for extraction.
+#[derive(Debug)]
+pub(crate) struct StagedEntry {
+    /// Position of the entry in the archive
+    pub index: usize,
+    pub entry: ArchiveEntry,
+    pub output_path: PathBuf,
+    /// Path in the staging directory the entry is written to
+    pub staged_path: PathBuf,
+}
+
+/// Resolve the output path of an archive entry.
+///
+/// Directory entries are staged to be created on commit.
+/// Returns `None` for directories, entries excluded by the filter,
+/// and entries whose path is not safe to extract.
+/// The entry kind is only resolved when extras are routed to their own directory.
+pub(crate) fn entry_target(
+    entry: &ArchiveEntry,
+    archive_name: &str,
+    extract_to: &Path,
+    options: &ExtractOptions,
+    staging: &mut Staging,
+    kind: impl FnOnce() -> EntryKind,
+) -> Option<PathBuf> {
+    if entry.is_dir {
+        if let Some(output_path) = entry_output_path(&entry.name, extract_to, options.filenames) {
+            staging.stage_dir(output_path, entry.modified);
+        }
+        return None;
+    }
+    if !options.filter.allows(&entry.name) {
+        return None;
+    }
+    let entry_dir = options.entry_dir(extract_to, archive_name, kind);
+    entry_output_path(&entry.name, &entry_dir, options.filenames)
+}
+
+/// Stage a file entry for the claimed output path.
+/// Returns `None` if the file already exists and existing files are kept.
+pub(crate) fn stage_file(
+    index: usize,
+    entry: ArchiveEntry,
+    output_path: PathBuf,
+    options: &ExtractOptions,
+    staging: &mut Staging,
+) -> Option<StagedEntry> {
+    if output_path.exists() && !options.overwrite {
+        staging.skip_existing();
+        return None;
+    }
+    let staged_path = staging.stage_file(output_path.clone());
+    if options.filenames.transliterate && !entry.name.is_ascii() {
+        staging.record_original_name(output_path.clone(), &entry.name);
+    }
+    Some(StagedEntry {
+        index,
+        entry,
+        output_path,
+        staged_path,
+    })
+}
+
+/// Resolve, claim and stage an archive entry in one step, for archives read as a stream.
+/// Returns `None` if the entry is not written to a file.
+pub(crate) fn stage_entry(
+    index: usize,
+    entry: ArchiveEntry,
+    archive_name: &str,
+    extract_to: &Path,
+    options: &ExtractOptions,
+    staging: &mut Staging,
+    kind: impl FnOnce() -> EntryKind,
+) -> Option<StagedEntry> {
+    let output_path = entry_target(&entry, archive_name, extract_to, options, staging, kind)?;
+    let output_path = options
+        .names
+        .claim(output_path, &entry_source(archive_name, &entry.name));
+    stage_file(index, entry, output_path, options, staging)
+}
+
+/// Create the staged file for writing the entry data.
+pub(crate) fn create_file(staged: &StagedEntry) -> anyhow::Result<File> {
+    File::create(&staged.staged_path).with_context(|| {
+        format!(
+            "Failed to create output file: {}",
+            staged.output_path.display()
+        )
+    })
+}
+
+/// Restore the entry metadata on the written file.
+pub(crate) fn finish_file(staged: &StagedEntry, file: &File) -> anyhow::Result<()> {
+    archive::set_file_metadata(
+        file,
+        &staged.staged_path,
+        staged.entry.modified,
+        staged.entry.mode,
+    )
+}
+
+/// Source of an entry for claiming its output path.
+pub(crate) fn entry_source(archive_name: &str, entry_name: &str) -> String {
+    format!("{archive_name}/{entry_name}")
+}
+
+/// Get the output path for an archive entry.
+/// Returns `None` if the entry path is not safe to extract.
+pub(crate) fn entry_output_path(
+    entry_name: &str,
+    extract_to: &Path,
+    filenames: FilenameOptions,
+) -> Option<PathBuf> {
+    let components = utils::enclosed_zip_entry_components(entry_name)?;
+
+    // Sanitize each component of the path
+    let sanitized_path: PathBuf = components
+        .iter()
+        .map(|name| filenames.apply(name))
+        .collect();
+
+    let mut output_path = extract_to.join(sanitized_path);
+
+    if let Some(extension) = output_path.extension()
+        && extension == "aiff"
+    {
+        output_path.set_extension("aif");
+    }
+    Some(output_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::EntryFilter;
+    use crate::test_utils::TempDir;
+
+    fn file(name: &str) -> ArchiveEntry {
+        ArchiveEntry {
+            name: name.to_string(),
+            is_dir: false,
+            size: 4,
+            modified: None,
+            mode: None,
+        }
+    }
+
+    #[test]
+    fn entry_targets() {
+        let dir = TempDir::new("entry-target");
+        let options = ExtractOptions {
+            filter: EntryFilter::new(&[], &["*.txt".to_string()]).expect("filter"),
+            extras_dir: Some(PathBuf::from("Extras")),
+            ..ExtractOptions::default()
+        };
+        let mut staging = Staging::create(&dir).expect("create staging");
+        let target = |entry: &ArchiveEntry, staging: &mut Staging| {
+            entry_target(entry, "Artist - Album.zip", &dir, &options, staging, || {
+                crate::classify::classify(&entry.name, None)
+            })
+        };
+        assert_eq!(
+            target(&file("a:b.aiff"), &mut staging),
+            Some(dir.join("a_b.aif"))
+        );
+        assert_eq!(
+            target(&file("Booklet.pdf"), &mut staging),
+            Some(
+                dir.join("Extras")
+                    .join("Artist - Album")
+                    .join("Booklet.pdf")
+            )
+        );
+        // Nested archives are not extras, so they are extracted next to the audio files
+        assert_eq!(
+            target(&file("Bonus Tracks.zip"), &mut staging),
+            Some(dir.join("Bonus Tracks.zip"))
+        );
+        assert_eq!(target(&file("notes.txt"), &mut staging), None);
+        assert_eq!(target(&file("../escape.flac"), &mut staging), None);
+        let directory = ArchiveEntry {
+            is_dir: true,
+            ..file("Album/")
+        };
+        assert_eq!(target(&directory, &mut staging), None);
+        assert_eq!(options.filter.skipped(), 1);
+    }
+
+    #[test]
+    fn existing_files_are_skipped() {
+        let dir = TempDir::new("entry-existing");
+        std::fs::write(dir.join("01 Track.flac"), b"old").expect("write file");
+        let mut staging = Staging::create(&dir).expect("create staging");
+        let options = ExtractOptions::default();
+        let existing = dir.join("01 Track.flac");
+        assert!(
+            stage_file(
+                0,
+                file("01 Track.flac"),
+                existing.clone(),
+                &options,
+                &mut staging
+            )
+            .is_none()
+        );
+
+        let overwrite = ExtractOptions {
+            overwrite: true,
+            ..ExtractOptions::default()
+        };
+        let staged = stage_file(
+            1,
+            file("01 Track.flac"),
+            existing.clone(),
+            &overwrite,
+            &mut staging,
+        )
+        .expect("staged entry");
+        std::fs::write(&staged.staged_path, b"new").expect("write staged file");
+        let extracted = staging.commit().expect("commit");
+        assert_eq!(extracted.skipped_existing, 1);
+        assert_eq!(extracted.overwritten, 1);
+        assert_eq!(std::fs::read(&existing).expect("read"), b"new");
+    }
+}
@@ -0,0 +1,330 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use indicatif::{MultiProgress, ProgressDrawTarget};
+use serde_json::json;
+
+use crate::manifest::ErrorCategory;
+use crate::report::ExtractedFiles;
+
+/// Minimum time between progress events for a single download.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Machine-readable event output format.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum EventFormat {
+    /// One JSON object per line
+    #[default]
+    Ndjson,
+}
+
+/// A lifecycle event of a download or an archive extraction.
+#[derive(Debug, Clone, Copy)]
+pub enum Event<'a> {
+    /// URL added to the download queue
+    Queued { url: &'a str },
+    /// Download started
+    Started {
+        url: &'a str,
+        filename: &'a str,
+        total_bytes: u64,
+    },
+    /// Download progress
+    Progress {
+        url: &'a str,
+        bytes: u64,
+        total_bytes: u64,
+    },
+    /// Download started over from the beginning
+    Retry { url: &'a str, reason: &'a str },
+    /// Download finished, or skipped because the file already exists
+    Finished {
+        url: &'a str,
+        path: &'a Path,
+        existing: bool,
+        duration: Duration,
+    },
+    /// Archive extracted
+    Extracted {
+        archive: &'a Path,
+        extracted: &'a ExtractedFiles,
+    },
+    /// Download or extraction failed
+    Failed {
+        url: Option<&'a str>,
+        archive: Option<&'a Path>,
+        error: &'a anyhow::Error,
+    },
+    /// Image removed after extraction
+    ImageRemoved { path: &'a Path },
+}
+
+/// Destination for lifecycle events, shared between concurrent tasks.
+/// Events are discarded when the sink is disabled.
+#[derive(Clone, Default)]
+pub struct EventSink {
+    writer: Option<Arc<Mutex<Box<dyn Write + Send>>>>,
+}
+
+/// Sends throttled progress events for a single download.
+pub struct ProgressEvents {
+    events: EventSink,
+    url: String,
+    total_bytes: u64,
+    bytes: u64,
+    last_sent: Instant,
+}
+
+impl Event<'_> {
+    /// Event name used in the output.
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Queued { .. } => "queued",
+            Self::Started { .. } => "started",
+            Self::Progress { .. } => "progress",
+            Self::Retry { .. } => "retry",
+            Self::Finished { .. } => "finished",
+            Self::Extracted { .. } => "extracted",
+            Self::Failed { .. } => "failed",
+            Self::ImageRemoved { .. } => "image-removed",
+        }
+    }
+
+    /// Event as a JSON object with the event name and a timestamp.
+    #[must_use]
+    pub fn to_json(&self, timestamp: &str) -> serde_json::Value {
+        let mut value = match *self {
+            Self::Queued { url } => json!({ "url": url }),
+            Self::Started {
+                url,
+                filename,
+                total_bytes,
+            } => json!({ "url": url, "filename": filename, "total_bytes": total_bytes }),
+            Self::Progress {
+                url,
+                bytes,
+                total_bytes,
+            } => json!({ "url": url, "bytes": bytes, "total_bytes": total_bytes }),
+            Self::Retry { url, reason } => json!({ "url": url, "reason": reason }),
+            Self::Finished {
+                url,
+                path,
+                existing,
+                duration,
+            } => json!({
+                "url": url,
+                "path": path.to_string_lossy(),
+                "existing": existing,
+                "duration_seconds": duration.as_secs_f64(),
+            }),
+            Self::Extracted { archive, extracted } => json!({
+                "archive": archive.to_string_lossy(),
+                "files": extracted.files.len(),
+                "overwritten": extracted.overwritten,
+                "skipped_existing": extracted.skipped_existing,
+            }),
+            Self::Failed {
+                url,
+                archive,
+                error,
+            } => json!({
+                "url": url,
+                "archive": archive.map(Path::to_string_lossy),
+                "error": format!("{error:#}"),
+                "error_category": ErrorCategory::of(error).as_str(),
+            }),
+            Self::ImageRemoved { path } => json!({ "path": path.to_string_lossy() }),
+        };
+        value["event"] = json!(self.name());
+        value["timestamp"] = json!(timestamp);
+        value
+    }
+}
+
+impl EventSink {
+    /// Write events to standard output.
+    #[must_use]
+    pub fn stdout() -> Self {
+        Self::new(Box::new(io::stdout()))
+    }
+
+    /// Write events to an open file descriptor, for example `3` for `3>events.ndjson`.
+    /// Only supported on Unix, since other platforms have no `/dev/fd`.
+    #[cfg(unix)]
+    pub fn file_descriptor(fd: u32) -> anyhow::Result<Self> {
+        let path = format!("/dev/fd/{fd}");
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open file descriptor for events: {fd}"))?;
+        Ok(Self::new(Box::new(file)))
+    }
+
+    /// Only supported on Unix, since other platforms have no `/dev/fd`.
+    #[cfg(not(unix))]
+    pub fn file_descriptor(fd: u32) -> anyhow::Result<Self> {
+        anyhow::bail!("Writing events to file descriptor {fd} is only supported on Unix")
+    }
+
+    #[must_use]
+    pub fn new(writer: Box<dyn Write + Send>) -> Self {
+        Self {
+            writer: Some(Arc::new(Mutex::new(writer))),
+        }
+    }
+
+    #[must_use]
+    pub const fn is_enabled(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    /// Write the event as a single JSON line.
+    /// Write errors are ignored so a closed event reader does not fail the downloads.
+    pub fn emit(&self, event: &Event) {
+        let Some(writer) = &self.writer else {
+            return;
+        };
+        let line = event.to_json(&jiff::Timestamp::now().to_string());
+        if let Ok(mut writer) = writer.lock() {
+            let _ = writeln!(writer, "{line}");
+            let _ = writer.flush();
+        }
+    }
+
+    /// Create the progress bar container,
+    /// hidden when events replace the progress display.
+    #[must_use]
+    pub fn multi_progress(&self) -> MultiProgress {
+        if self.is_enabled() {
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+        } else {
+            MultiProgress::new()
+        }
+    }
+
+    /// Create progress event tracking for a download.
+    #[must_use]
+    pub fn progress(&self, url: &str, total_bytes: u64) -> ProgressEvents {
+        ProgressEvents {
+            events: self.clone(),
+            url: url.to_string(),
+            total_bytes,
+            bytes: 0,
+            last_sent: Instant::now(),
+        }
+    }
+}
+
+impl fmt::Debug for EventSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventSink")
+            .field("enabled", &self.is_enabled())
+            .finish()
+    }
+}
+
+impl ProgressEvents {
+    /// Add downloaded bytes, sending a progress event if enough time has passed since the last one.
+    pub fn inc(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        if self.events.is_enabled() && self.last_sent.elapsed() >= PROGRESS_INTERVAL {
+            self.last_sent = Instant::now();
+            self.send();
+        }
+    }
+
+    /// Send the final progress event.
+    pub fn finish(&self) {
+        self.send();
+    }
+
+    fn send(&self) {
+        self.events.emit(&Event::Progress {
+            url: &self.url,
+            bytes: self.bytes,
+            total_bytes: self.total_bytes,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// Writer that collects the output for inspection.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().expect("lock").extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn event_json() {
+        let extracted = ExtractedFiles {
+            files: vec![PathBuf::from("01.flac"), PathBuf::from("02.flac")],
+            new_files: vec![PathBuf::from("02.flac")],
+            overwritten: 1,
+            skipped_existing: 0,
+            nested_errors: Vec::new(),
+        };
+        let event = Event::Extracted {
+            archive: Path::new("album.zip"),
+            extracted: &extracted,
+        };
+        let json = event.to_json("2026-01-01T00:00:00Z");
+        assert_eq!(json["event"], "extracted");
+        assert_eq!(json["archive"], "album.zip");
+        assert_eq!(json["files"], 2);
+        assert_eq!(json["timestamp"], "2026-01-01T00:00:00Z");
+
+        let error = anyhow::Error::new(io::Error::other("disk full"));
+        let json = Event::Failed {
+            url: Some("https://example.com/a"),
+            archive: None,
+            error: &error,
+        }
+        .to_json("");
+        assert_eq!(json["event"], "failed");
+        assert_eq!(json["error_category"], "io");
+        assert_eq!(json["archive"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn emit_ndjson_lines() {
+        let buffer = Buffer::default();
+        let events = EventSink::new(Box::new(buffer.clone()));
+        events.emit(&Event::Queued {
+            url: "https://example.com/a",
+        });
+        let mut progress = events.progress("https://example.com/a", 10);
+        progress.inc(10);
+        progress.finish();
+        EventSink::default().emit(&Event::Queued { url: "ignored" });
+
+        let output = String::from_utf8(buffer.0.lock().expect("lock").clone()).expect("utf8");
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("json line"))
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "queued");
+        assert_eq!(lines[1]["event"], "progress");
+        assert_eq!(lines[1]["bytes"], 10);
+    }
+}
@@ -0,0 +1,137 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::Context;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+/// Include and exclude glob patterns for archive entry paths.
+///
+/// Patterns are case-insensitive and `*` also matches across directories,
+/// so `*.jpg` matches images in any subdirectory.
+/// Cloning shares the skipped entry count.
+#[derive(Debug, Clone, Default)]
+pub struct EntryFilter {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+    skipped: Arc<AtomicUsize>,
+}
+
+impl EntryFilter {
+    /// Create filter from include and exclude patterns.
+    /// With include patterns, only matching entries are extracted.
+    pub fn new(include: &[String], exclude: &[String]) -> anyhow::Result<Self> {
+        Ok(Self {
+            include: build_glob_set(include)?,
+            exclude: build_glob_set(exclude)?,
+            skipped: Arc::default(),
+        })
+    }
+
+    /// Check if the entry with the given path inside the archive should be extracted.
+    /// Counts the entries that are filtered out.
+    #[must_use]
+    pub fn allows(&self, entry_name: &str) -> bool {
+        let included = self
+            .include
+            .as_ref()
+            .is_none_or(|include| include.is_match(entry_name));
+        let excluded = self
+            .exclude
+            .as_ref()
+            .is_some_and(|exclude| exclude.is_match(entry_name));
+        if included && !excluded {
+            return true;
+        }
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    /// Copy of the filter with its own skipped entry count,
+    /// for an extraction attempt that is only counted if it succeeds.
+    #[must_use]
+    pub fn scratch(&self) -> Self {
+        Self {
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            skipped: Arc::default(),
+        }
+    }
+
+    /// Add the entries skipped by the scratch filter to the count.
+    pub fn merge(&self, scratch: &Self) {
+        self.skipped.fetch_add(scratch.skipped(), Ordering::Relaxed);
+    }
+
+    /// Number of entries skipped by the filter so far.
+    #[must_use]
+    pub fn skipped(&self) -> usize {
+        self.skipped.load(Ordering::Relaxed)
+    }
+}
+
+/// Print the number of archive entries skipped by the filter.
+pub fn print_skipped(filter: &EntryFilter) {
+    match filter.skipped() {
+        0 => {}
+        1 => println!("Skipped 1 filtered archive entry"),
+        skipped => println!("Skipped {skipped} filtered archive entries"),
+    }
+}
+
+/// Compile the patterns to a single matcher.
+/// Returns `None` if there are no patterns.
+fn build_glob_set(patterns: &[String]) -> anyhow::Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .case_insensitive(true)
+            .build()
+            .with_context(|| format!("Invalid glob pattern: '{pattern}'"))?;
+        builder.add(glob);
+    }
+    Ok(Some(builder.build()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(values: &[&str]) -> Vec<String> {
+        values.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn default_filter_allows_everything() {
+        let filter = EntryFilter::default();
+        assert!(filter.allows("Album/01 Track.flac"));
+        assert_eq!(filter.skipped(), 0);
+    }
+
+    #[test]
+    fn exclude_patterns_match_in_subdirectories() {
+        let filter = EntryFilter::new(&[], &patterns(&["*.jpg", "*.pdf"])).unwrap();
+        let shared = filter.clone();
+        assert!(filter.allows("Album/01 Track.flac"));
+        assert!(!filter.allows("cover.jpg"));
+        assert!(!filter.allows("Album/Scans/Back.JPG"));
+        assert!(!filter.allows("Album/Booklet.pdf"));
+        assert_eq!(shared.skipped(), 3);
+    }
+
+    #[test]
+    fn include_and_exclude_patterns() {
+        let filter = EntryFilter::new(&patterns(&["*.flac"]), &patterns(&["*bonus*"])).unwrap();
+        assert!(filter.allows("01 Track.flac"));
+        assert!(!filter.allows("notes.txt"));
+        assert!(!filter.allows("Bonus/02 Track.flac"));
+        assert_eq!(filter.skipped(), 2);
+    }
+
+    #[test]
+    fn invalid_pattern_is_an_error() {
+        assert!(EntryFilter::new(&patterns(&["[a-"]), &[]).is_err());
+    }
+}
@@ -0,0 +1,224 @@
+use std::fmt::Write;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use rusqlite::{Connection, OptionalExtension, params};
+use sha2::{Digest, Sha256};
+
+const HISTORY_FILE_NAME: &str = "history.db";
+
+/// Persistent history of downloaded items,
+/// used to skip items that have already been downloaded in earlier runs.
+#[derive(Debug)]
+pub struct History {
+    connection: Connection,
+}
+
+/// A downloaded item stored in the history.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistoryRecord {
+    /// Download URL
+    pub url: String,
+    /// Name of the downloaded file or archive
+    pub filename: String,
+    /// Downloaded file size in bytes, not known for archives extracted while downloading
+    pub size: Option<u64>,
+    /// SHA-256 hash of the downloaded file as a hex string
+    pub sha256: Option<String>,
+    /// Archive extraction result, `None` for files that are not archives
+    pub extraction: Option<String>,
+}
+
+impl History {
+    /// Open the history database at the given path, creating it if needed.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).with_context(|| {
+                format!("Failed to create history directory: {}", parent.display())
+            })?;
+        }
+        let connection = Connection::open(path)
+            .with_context(|| format!("Failed to open history database: {}", path.display()))?;
+        Self::init(connection)
+    }
+
+    /// Open a temporary history that is not saved to disk.
+    pub fn open_in_memory() -> anyhow::Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(connection: Connection) -> anyhow::Result<Self> {
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS downloads (
+                    url_id TEXT PRIMARY KEY,
+                    url TEXT NOT NULL,
+                    filename TEXT NOT NULL,
+                    size INTEGER,
+                    sha256 TEXT,
+                    extraction TEXT,
+                    downloaded_at TEXT NOT NULL
+                )",
+            )
+            .context("Failed to create history table")?;
+        Ok(Self { connection })
+    }
+
+    /// Check if the item with the given download URL has already been downloaded.
+    pub fn contains(&self, url: &str) -> anyhow::Result<bool> {
+        let found = self
+            .connection
+            .query_row(
+                "SELECT 1 FROM downloads WHERE url_id = ?1",
+                params![url_id(url)],
+                |_| Ok(()),
+            )
+            .optional()
+            .context("Failed to query download history")?;
+        Ok(found.is_some())
+    }
+
+    /// Get the stored record for the download URL.
+    pub fn get(&self, url: &str) -> anyhow::Result<Option<HistoryRecord>> {
+        self.connection
+            .query_row(
+                "SELECT url, filename, size, sha256, extraction FROM downloads WHERE url_id = ?1",
+                params![url_id(url)],
+                |row| {
+                    Ok(HistoryRecord {
+                        url: row.get(0)?,
+                        filename: row.get(1)?,
+                        size: row.get(2)?,
+                        sha256: row.get(3)?,
+                        extraction: row.get(4)?,
+                    })
+                },
+            )
+            .optional()
+            .context("Failed to query download history")
+    }
+
+    /// Add a downloaded item, replacing an earlier record for the same item.
+    pub fn record(&self, record: &HistoryRecord) -> anyhow::Result<()> {
+        self.connection
+            .execute(
+                "INSERT OR REPLACE INTO downloads
+                    (url_id, url, filename, size, sha256, extraction, downloaded_at)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    url_id(&record.url),
+                    record.url,
+                    record.filename,
+                    record.size,
+                    record.sha256,
+                    record.extraction,
+                    jiff::Timestamp::now().to_string(),
+                ],
+            )
+            .with_context(|| format!("Failed to save download history for: {}", record.url))?;
+        Ok(())
+    }
+}
+
+/// Default history database location in the user data directory.
+#[must_use]
+pub fn default_path() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("bandcamp-dl").join(HISTORY_FILE_NAME))
+}
+
+/// Get the item ID from a download URL.
+///
+/// For example `album/178dd6dd97f4418b69` for `https://p4.bcbits.com/download/album/178dd6dd97f4418b69?id=1`.
+/// The ID stays the same when the query parameters of the download link change.
+#[must_use]
+pub fn url_id(url: &str) -> &str {
+    let url = url.split(['?', '#']).next().unwrap_or(url);
+    url.split_once("/download/")
+        .map_or(url, |(_, id)| id)
+        .trim_end_matches('/')
+}
+
+/// Calculate the SHA-256 hash of the file as a lowercase hex string.
+pub fn file_sha256(path: &Path) -> anyhow::Result<String> {
+    let mut file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open file for hashing: {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 256 * 1024];
+    loop {
+        let count = file
+            .read(&mut buffer)
+            .with_context(|| format!("Failed to read file for hashing: {}", path.display()))?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buffer[..count]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn url_ids() {
+        assert_eq!(
+            url_id("https://p4.bcbits.com/download/album/178dd6dd97f4418b69?id=1&ts=2"),
+            "album/178dd6dd97f4418b69"
+        );
+        assert_eq!(
+            url_id("https://p4.bcbits.com/download/track/1b37d456848ecb79c2"),
+            "track/1b37d456848ecb79c2"
+        );
+        assert_eq!(
+            url_id("https://example.com/file.zip#part"),
+            "https://example.com/file.zip"
+        );
+    }
+
+    #[test]
+    fn record_and_find_downloads() {
+        let history = History::open_in_memory().expect("open history");
+        let url = "https://p4.bcbits.com/download/album/10?id=1";
+        assert!(!history.contains(url).expect("query"));
+
+        let record = HistoryRecord {
+            url: url.to_string(),
+            filename: "Artist - Album.zip".to_string(),
+            size: Some(1234),
+            sha256: Some("ab".repeat(32)),
+            extraction: Some("Extracted 12 files".to_string()),
+        };
+        history.record(&record).expect("record");
+        assert!(
+            history
+                .contains("https://p4.bcbits.com/download/album/10?id=2")
+                .expect("query")
+        );
+        assert_eq!(history.get(url).expect("query"), Some(record));
+        assert!(
+            !history
+                .contains("https://p4.bcbits.com/download/album/11")
+                .expect("query")
+        );
+    }
+
+    #[test]
+    fn sha256_of_file() {
+        let dir = TempDir::new("sha256");
+        let path = dir.join("file");
+        std::fs::write(&path, b"abc").expect("write file");
+        assert_eq!(
+            file_sha256(&path).expect("hash"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -1,20 +1,59 @@
+pub mod archive;
+pub mod artwork;
+pub mod bandcamp;
+pub mod classify;
+pub mod collision;
+pub mod config;
+pub mod entry;
+pub mod events;
+pub mod filter;
+pub mod history;
+pub mod limits;
+pub mod manifest;
+pub mod metadata;
+mod order;
+pub mod rename;
+pub mod report;
+pub mod staging;
+pub mod stream;
+pub mod template;
 pub mod utils;
 
+#[cfg(test)]
+mod test_utils;
+
+use std::collections::VecDeque;
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::LazyLock;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::{Context, Error, anyhow};
-use colored::Colorize;
+use anyhow::{Context, Error};
 use futures::StreamExt;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use regex::Regex;
 use reqwest::Client;
-use reqwest::header::{CONTENT_DISPOSITION, CONTENT_LENGTH, HeaderMap};
+use reqwest::header::{CONTENT_DISPOSITION, CONTENT_LENGTH, HeaderMap, LAST_MODIFIED};
 use tokio::io::{AsyncWriteExt, BufWriter};
 use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio_util::io::{StreamReader, SyncIoBridge};
 use zip::ZipArchive;
+use zip::extra_fields::ExtraField;
+use zip::read::ZipFile;
+
+use crate::archive::{ArchiveDisposition, ArchiveEntry, ArchiveFormat};
+use crate::classify::EntryKind;
+use crate::collision::NameRegistry;
+use crate::entry::StagedEntry;
+use crate::events::{Event, EventSink, ProgressEvents};
+use crate::filter::EntryFilter;
+use crate::limits::{ExtractLimits, SizeBudget};
+use crate::order::{InputOrder, Place};
+use crate::report::{ArchiveReport, ExtractedFiles};
+use crate::staging::Staging;
+use crate::utils::FilenameOptions;
 
 /// Regex to match filename in `CONTENT_DISPOSITION` header
 static RE_FILENAME: LazyLock<Regex> =
@@ -22,43 +61,200 @@ static RE_FILENAME: LazyLock<Regex> =
 
 const PROGRESS_BAR_CHARS: &str = "=>-";
 const PROGRESS_BAR_DOWNLOAD_TEMPLATE: &str = "[{elapsed_precise}] {bar:40.cyan/blue} [{percent:>3}%] {bytes:>10}/{total_bytes:>10} ({bytes_per_sec:>11}) {msg}";
-const PROGRESS_BAR_UNZIP_TEMPLATE: &str =
-    "[{elapsed_precise}] {bar:40.magenta/blue} {pos:>3}/{len:3} {msg}";
+const PROGRESS_BAR_UNZIP_TEMPLATE: &str = "[{elapsed_precise}] {bar:40.magenta/blue} [{percent:>3}%] {bytes:>10}/{total_bytes:>10} ({bytes_per_sec:>11}) {msg}";
+
+/// Minimum total uncompressed size for extracting archive entries in parallel.
+const PARALLEL_EXTRACT_MIN_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Options for downloading files.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    /// Overwrite existing files
+    pub overwrite: bool,
+    /// Output filename options
+    pub filenames: FilenameOptions,
+    /// Output paths claimed during the run
+    pub names: NameRegistry,
+    /// Set file modification time from the `Last-Modified` header
+    pub remote_time: bool,
+    /// Extract zip archives while downloading with these options,
+    /// instead of saving the archive to disk first
+    pub stream_extract: Option<ExtractOptions>,
+    /// Lifecycle event output, replaces the progress bars when enabled
+    pub events: EventSink,
+    /// Do not start new downloads after the first failure, and stop the running ones
+    pub fail_fast: bool,
+    /// Set after the first failure in fail-fast mode
+    pub cancel: CancelToken,
+}
+
+/// A successfully downloaded URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Downloaded {
+    /// File saved to disk
+    File {
+        path: PathBuf,
+        /// The file replaced an existing file
+        overwritten: bool,
+    },
+    /// File not downloaded because it already exists
+    Existing(PathBuf),
+    /// Zip archive extracted while downloading, without saving the archive to disk
+    Extracted {
+        /// Path the archive would have been saved to
+        archive: PathBuf,
+        extracted: ExtractedFiles,
+    },
+}
+
+/// Result of downloading a single URL.
+#[derive(Debug)]
+pub struct DownloadResult {
+    /// Download URL
+    pub url: String,
+    /// Downloaded file or the error
+    pub result: Result<Downloaded, Error>,
+    /// Time spent downloading
+    pub duration: Duration,
+}
+
+/// Options for extracting archives.
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+    /// Overwrite existing files
+    pub overwrite: bool,
+    /// Output filename options
+    pub filenames: FilenameOptions,
+    /// Output paths claimed during the run
+    pub names: NameRegistry,
+    /// Number of parallel workers per archive for large archives
+    pub entry_jobs: usize,
+    /// Resource limits for each archive
+    pub limits: ExtractLimits,
+    /// Entry paths to extract
+    pub filter: EntryFilter,
+    /// Directory for extras like booklets, videos and text files,
+    /// relative to the extraction directory, or absolute for a central directory.
+    /// Extras are kept next to the audio files when not set.
+    pub extras_dir: Option<PathBuf>,
+    /// Maximum depth for extracting archives found inside extracted archives, 0 to disable
+    pub nested_depth: usize,
+    /// What to do with nested archives after extracting them
+    pub nested_disposition: ArchiveDisposition,
+    /// Lifecycle event output, replaces the progress bars when enabled
+    pub events: EventSink,
+    /// Do not start new archive extractions after the first failure, and stop the running ones
+    pub fail_fast: bool,
+    /// Set after the first failure in fail-fast mode
+    pub cancel: CancelToken,
+}
+
+/// The task was not started or was stopped because an earlier task failed in fail-fast mode.
+#[derive(Debug)]
+pub struct Cancelled;
+
+/// Flag that stops the running downloads and extractions once set.
+/// Cloning shares the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Cancelled after an earlier failure")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+impl ExtractOptions {
+    /// Get the directory to extract an archive entry to.
+    /// Extras go to a subdirectory named after the archive in the extras directory, if set.
+    /// The entry kind is only resolved when needed.
+    pub(crate) fn entry_dir(
+        &self,
+        extract_to: &Path,
+        archive_name: &str,
+        kind: impl FnOnce() -> EntryKind,
+    ) -> PathBuf {
+        match &self.extras_dir {
+            Some(extras_dir) if kind() == EntryKind::Extra => extract_to
+                .join(extras_dir)
+                .join(self.filenames.apply(classify::archive_stem(archive_name))),
+            _ => extract_to.to_path_buf(),
+        }
+    }
+}
 
 /// Download given URLs concurrently.
-/// Returns a list of results with the file paths for successful downloads.
+/// Returns the results in the same order as the URLs.
 pub async fn download_urls(
     urls: Vec<String>,
     absolute_output_path: &Path,
-    force: bool,
-) -> anyhow::Result<Vec<Result<PathBuf, Error>>> {
+    options: &DownloadOptions,
+) -> anyhow::Result<Vec<DownloadResult>> {
     let client = Client::builder()
         .connect_timeout(Duration::new(5, 0))
         .build()
         .context("Failed to create client")?;
 
-    let multi_progress = Arc::new(MultiProgress::new());
+    let multi_progress = Arc::new(options.events.multi_progress());
     let semaphore = create_semaphore_for_num_physical_cpus();
+    // Permits are taken in input order, so a download waiting for the earlier
+    // downloads to claim their paths never holds up one of them
+    let permit_order = InputOrder::new();
+    let claim_order = InputOrder::new();
     let tasks: Vec<_> = urls
         .into_iter()
-        .map(|url| {
+        .enumerate()
+        .map(|(index, url)| {
+            options.events.emit(&Event::Queued { url: &url });
             let client = client.clone();
             let progress = Arc::clone(&multi_progress);
             let sem = Arc::clone(&semaphore);
             let path = absolute_output_path.to_path_buf();
+            let options = options.clone();
+            let permit_place = permit_order.place(index);
+            let claim_place = claim_order.place(index);
             tokio::spawn(async move {
+                permit_place.wait().await;
                 let permit: SemaphorePermit = sem
                     .acquire()
                     .await
                     .expect("Failed to acquire permit for download");
-                let result = download_file(&client, &path, &url, progress, force).await;
+                drop(permit_place);
+                let start = Instant::now();
+                let result = if options.cancel.is_cancelled() {
+                    Err(Error::new(Cancelled))
+                } else {
+                    download_file(&client, &path, &url, claim_place, progress, &options).await
+                };
                 drop(permit);
-                result
+                if result.is_err() && options.fail_fast {
+                    options.cancel.cancel();
+                }
+                let download = DownloadResult {
+                    url,
+                    result,
+                    duration: start.elapsed(),
+                };
+                emit_download_events(&options.events, &download);
+                download
             })
         })
         .collect();
 
-    let results: Vec<Result<PathBuf, _>> = futures::future::join_all(tasks)
+    let results: Vec<DownloadResult> = futures::future::join_all(tasks)
         .await
         .into_iter()
         .map(|res| res.expect("Download future failed"))
@@ -67,162 +263,459 @@ pub async fn download_urls(
     Ok(results)
 }
 
-/// Extract all zip files concurrently.
-pub async fn extract_zip_files(zip_files: Vec<PathBuf>, overwrite: bool) -> usize {
-    let multi_progress = Arc::new(MultiProgress::new());
+/// Extract all zip, tar and 7z archives concurrently.
+/// Returns a report for each archive in the same order as the input.
+pub async fn extract_archives(
+    zip_files: Vec<PathBuf>,
+    options: &ExtractOptions,
+) -> Vec<ArchiveReport> {
+    let multi_progress = Arc::new(options.events.multi_progress());
     let mut tasks = Vec::new();
     let semaphore = create_semaphore_for_num_physical_cpus();
-    for zip_path in zip_files {
+    let order = InputOrder::new();
+    for (index, zip_path) in zip_files.into_iter().enumerate() {
         let sem = Arc::clone(&semaphore);
         let progress = Arc::clone(&multi_progress);
+        let options = options.clone();
+        let place = order.place(index);
         tasks.push(tokio::spawn(async move {
-            let permit = sem
-                .acquire()
-                .await
-                .expect("Failed to acquire permit for unzip");
-            let result = extract_zip_file(zip_path, progress, overwrite).await;
-            drop(permit);
-            result
+            let events = options.events.clone();
+            let cancel = options.fail_fast.then(|| options.cancel.clone());
+            let result = plan_and_extract(zip_path.clone(), place, &sem, progress, options).await;
+            if let (Err(_), Some(cancel)) = (&result, cancel) {
+                cancel.cancel();
+            }
+            match &result {
+                Ok(extracted) => events.emit(&Event::Extracted {
+                    archive: &zip_path,
+                    extracted,
+                }),
+                Err(error) => events.emit(&Event::Failed {
+                    url: None,
+                    archive: Some(&zip_path),
+                    error,
+                }),
+            }
+            ArchiveReport {
+                archive: zip_path,
+                result,
+            }
         }));
     }
 
-    let total_unzipped_files: usize = futures::future::join_all(tasks)
+    futures::future::join_all(tasks)
         .await
         .into_iter()
         .map(|res| res.expect("Unzip future failed"))
-        .map(|result| {
-            result.unwrap_or_else(|e| {
-                eprintln!("{}", format!("Error: {e}").red());
-                0
-            })
-        })
-        .sum();
+        .collect()
+}
+
+/// Plan the archive once the archives before it have been planned,
+/// so colliding output paths are resolved in input order, and then extract it.
+async fn plan_and_extract(
+    path: PathBuf,
+    place: Place,
+    semaphore: &Semaphore,
+    multi_progress: Arc<MultiProgress>,
+    options: ExtractOptions,
+) -> anyhow::Result<ExtractedFiles> {
+    let cancelled = || options.cancel.is_cancelled();
+    place.wait().await;
+    if cancelled() {
+        return Err(Error::new(Cancelled));
+    }
+    let plan = {
+        let options = options.clone();
+        tokio::task::spawn_blocking(move || plan_archive(&path, &options)).await??
+    };
+    drop(place);
+
+    let _permit = semaphore
+        .acquire()
+        .await
+        .expect("Failed to acquire permit for unzip");
+    if cancelled() {
+        return Err(Error::new(Cancelled));
+    }
+    extract_archive_tree(plan, multi_progress, options).await
+}
 
-    total_unzipped_files
+/// Extract an archive, and then the archives found among the extracted files.
+/// The archive is moved to trash when done.
+async fn extract_archive_tree(
+    plan: ArchivePlan,
+    multi_progress: Arc<MultiProgress>,
+    options: ExtractOptions,
+) -> anyhow::Result<ExtractedFiles> {
+    let path = plan.path.clone();
+    let extracted = {
+        let multi_progress = Arc::clone(&multi_progress);
+        let options = options.clone();
+        tokio::task::spawn_blocking(move || extract_planned(plan, &multi_progress, &options))
+            .await??
+    };
+    ArchiveDisposition::Trash.apply(&path)?;
+    Ok(extract_nested_archives(extracted, multi_progress, &options).await)
+}
+
+/// Extract the archives found among the extracted files, up to the nested depth limit,
+/// and handle them according to the nested disposition.
+/// Only files with an archive extension are extracted as nested archives.
+/// Errors from nested archives are returned in the report without failing the outer archive.
+/// Nested archives are planned when they are extracted,
+/// so their colliding output paths are resolved in the order they are reached.
+/// Returns the extracted files, without the nested archives that were removed.
+async fn extract_nested_archives(
+    mut extracted: ExtractedFiles,
+    multi_progress: Arc<MultiProgress>,
+    options: &ExtractOptions,
+) -> ExtractedFiles {
+    let mut pending: VecDeque<(PathBuf, usize)> = std::mem::take(&mut extracted.files)
+        .into_iter()
+        .map(|file| (file, 1))
+        .collect();
+    while let Some((file, depth)) = pending.pop_front() {
+        if depth > options.nested_depth || ArchiveFormat::from_path(&file).is_none() {
+            extracted.files.push(file);
+            continue;
+        }
+        let result =
+            extract_archive_file(file.clone(), Arc::clone(&multi_progress), options.clone())
+                .await
+                .and_then(|files| {
+                    options.nested_disposition.apply(&file)?;
+                    Ok(files)
+                });
+        match result {
+            Ok(mut nested) => {
+                if options.nested_disposition == ArchiveDisposition::Keep {
+                    extracted.files.push(file);
+                } else {
+                    extracted.new_files.retain(|new_file| *new_file != file);
+                }
+                pending.extend(
+                    std::mem::take(&mut nested.files)
+                        .into_iter()
+                        .map(|file| (file, depth + 1)),
+                );
+                extracted.merge(nested);
+            }
+            Err(e) => {
+                extracted
+                    .nested_errors
+                    .push((file.clone(), format!("{e:#}")));
+                extracted.files.push(file);
+            }
+        }
+    }
+    extracted
 }
 
-/// Extract a single zip file with its own progress bar.
-async fn extract_zip_file(
+/// Extract a single archive with its own progress bar.
+/// Returns the extracted files.
+async fn extract_archive_file(
     path: PathBuf,
     multi_progress: Arc<MultiProgress>,
-    overwrite: bool,
-) -> anyhow::Result<usize> {
+    options: ExtractOptions,
+) -> anyhow::Result<ExtractedFiles> {
+    // Use spawn_blocking to avoid blocking the async runtime
+    tokio::task::spawn_blocking(move || {
+        let plan = plan_archive(&path, &options)?;
+        extract_planned(plan, &multi_progress, &options)
+    })
+    .await?
+}
+
+/// Archive with the output paths of its entries resolved and staged,
+/// ready to be extracted.
+pub(crate) struct ArchivePlan {
+    path: PathBuf,
+    format: ArchiveFormat,
+    name: String,
+    staging: Staging,
+    /// File entries that will be written to disk
+    entries: Vec<StagedEntry>,
+}
+
+/// Read the entries of a zip, tar or 7z archive, check them against the extraction limits,
+/// and resolve and claim the output path of each entry before writing anything.
+pub(crate) fn plan_archive(path: &Path, options: &ExtractOptions) -> anyhow::Result<ArchivePlan> {
+    let format = ArchiveFormat::sniff(path)
+        .or_else(|| ArchiveFormat::from_path(path))
+        .unwrap_or(ArchiveFormat::Zip);
+    let name = utils::get_filename_from_path(path)?;
     let extract_to = path
         .parent()
         .context("Failed to get parent dir")?
         .to_path_buf();
 
-    let zip_path = path.clone();
-    // Use spawn_blocking to avoid blocking the async runtime
-    tokio::task::spawn_blocking(move || -> anyhow::Result<usize> {
-        let file = std::fs::File::open(&zip_path)
-            .with_context(|| format!("Failed to open zip file: {}", zip_path.display()))?;
+    let mut zip_archive = None;
+    let entries = if format == ArchiveFormat::Zip {
+        let archive = zip_archive.insert(open_zip_archive(path)?);
+        list_zip_entries(archive, &name)?
+    } else {
+        archive::open_archive(path, format, &ProgressBar::hidden())?.list_entries()?
+    };
+    check_limits(path, &name, &entries, &options.limits)?;
 
-        let mut archive = ZipArchive::new(file)
-            .with_context(|| format!("Failed to read zip archive: {}", zip_path.display()))?;
+    let mut staging = Staging::create(&extract_to)?;
+    let mut targets = Vec::new();
+    for (index, entry) in entries.into_iter().enumerate() {
+        let kind = || match zip_archive.as_mut() {
+            Some(archive) => zip_entry_kind(archive, index, &entry.name),
+            // Reading ahead in tar and 7z archives means decompressing them,
+            // so extras are only detected by extension
+            None => classify::classify(&entry.name, None),
+        };
+        if let Some(output_path) =
+            entry::entry_target(&entry, &name, &extract_to, options, &mut staging, kind)
+        {
+            targets.push((index, entry, output_path));
+        }
+    }
 
-        let zip_file_name = utils::get_filename_from_path(&zip_path)?;
-        let total_entries = archive.len();
+    // Claim all paths together so collisions inside the archive do not depend on entry order
+    let claimed = options.names.claim_all(
+        targets
+            .iter()
+            .map(|(_, entry, output_path)| {
+                (output_path.clone(), entry::entry_source(&name, &entry.name))
+            })
+            .collect(),
+    );
+    let staged = targets
+        .into_iter()
+        .zip(claimed)
+        .filter_map(|((index, entry, _), output_path)| {
+            entry::stage_file(index, entry, output_path, options, &mut staging)
+        })
+        .collect();
+    Ok(ArchivePlan {
+        path: path.to_path_buf(),
+        format,
+        name,
+        staging,
+        entries: staged,
+    })
+}
 
-        let progress_bar = multi_progress.add(ProgressBar::new(total_entries as u64));
-        progress_bar.set_style(
-            ProgressStyle::default_bar()
-                .template(PROGRESS_BAR_UNZIP_TEMPLATE)?
-                .progress_chars(PROGRESS_BAR_CHARS),
-        );
-        progress_bar.set_message(zip_file_name);
-
-        // Count only files this run actually writes to disk.
-        // The reported total ignores directory entries and files skipped as already present.
-        let mut extracted_files = 0usize;
-        for i in 0..archive.len() {
-            progress_bar.inc(1);
-            let mut file = archive.by_index(i).with_context(|| {
-                format!(
-                    "Failed to access file at index {i} in {}",
-                    zip_path.display()
-                )
-            })?;
+/// Write the staged entries of the planned archive with its own progress bar,
+/// and move them into place.
+/// Returns the extracted files.
+pub(crate) fn extract_planned(
+    plan: ArchivePlan,
+    multi_progress: &MultiProgress,
+    options: &ExtractOptions,
+) -> anyhow::Result<ExtractedFiles> {
+    let ArchivePlan {
+        path,
+        format,
+        name,
+        staging,
+        entries,
+    } = plan;
+    // Count only files this run actually writes to disk.
+    // The reported total ignores directory entries and files skipped as already present.
+    let total_bytes: u64 = entries.iter().map(|staged| staged.entry.size).sum();
+    let progress_bar = multi_progress.add(ProgressBar::new(total_bytes));
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template(PROGRESS_BAR_UNZIP_TEMPLATE)?
+            .progress_chars(PROGRESS_BAR_CHARS),
+    );
+    progress_bar.set_message(name.clone());
 
-            let Some(file_path) = file.enclosed_name() else {
-                continue;
-            };
-
-            // Sanitize each component of the path
-            let sanitized_path: PathBuf = file_path
-                .components()
-                .map(|component| {
-                    if let std::path::Component::Normal(name) = component {
-                        PathBuf::from(utils::sanitize_filename(&name.to_string_lossy()))
-                    } else {
-                        PathBuf::from(component.as_os_str())
-                    }
+    let result = if format == ArchiveFormat::Zip {
+        extract_zip_file(&path, entries, &name, &progress_bar, options)
+    } else {
+        archive::open_archive(&path, format, &progress_bar).and_then(|mut archive| {
+            archive::extract_entries(archive.as_mut(), entries, &name, &progress_bar, options)
+        })
+    };
+    if let Err(error) = result {
+        progress_bar.abandon();
+        // Dropping the staging directory removes the partially extracted files
+        return Err(error);
+    }
+
+    progress_bar.finish();
+    staging.commit()
+}
+
+/// Write the staged zip entries, in parallel for large archives.
+fn extract_zip_file(
+    zip_path: &Path,
+    entries: Vec<StagedEntry>,
+    zip_file_name: &str,
+    progress_bar: &ProgressBar,
+    options: &ExtractOptions,
+) -> anyhow::Result<()> {
+    let total_bytes: u64 = entries.iter().map(|staged| staged.entry.size).sum();
+    let jobs = options.entry_jobs.clamp(1, entries.len().max(1));
+    let budget = options.limits.size_budget(zip_file_name, &options.cancel);
+    if jobs > 1 && total_bytes >= PARALLEL_EXTRACT_MIN_BYTES {
+        // Each worker needs its own archive handle since reading an entry seeks the file
+        std::thread::scope(|scope| {
+            // Collect to spawn all workers before joining any of them
+            #[allow(clippy::needless_collect)]
+            let workers: Vec<_> = split_entries(entries, jobs)
+                .into_iter()
+                .map(|bucket| {
+                    let budget = &budget;
+                    scope.spawn(move || {
+                        let mut archive = open_zip_archive(zip_path)?;
+                        extract_zip_entries(&mut archive, &bucket, progress_bar, budget)
+                    })
                 })
                 .collect();
+            workers
+                .into_iter()
+                .try_for_each(|worker| worker.join().expect("Unzip worker panicked"))
+        })
+    } else {
+        extract_zip_entries(
+            &mut open_zip_archive(zip_path)?,
+            &entries,
+            progress_bar,
+            &budget,
+        )
+    }
+}
 
-            let mut output_path = extract_to.join(sanitized_path);
+/// Open zip file for reading.
+fn open_zip_archive(path: &Path) -> anyhow::Result<ZipArchive<std::fs::File>> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open zip file: {}", path.display()))?;
+    ZipArchive::new(file).with_context(|| format!("Failed to read zip archive: {}", path.display()))
+}
 
-            if let Some(extension) = output_path.extension()
-                && extension == "aiff"
-            {
-                output_path.set_extension("aif");
-            }
+/// List the entries of a zip archive from the central directory, in index order.
+fn list_zip_entries(
+    archive: &mut ZipArchive<std::fs::File>,
+    zip_file_name: &str,
+) -> anyhow::Result<Vec<ArchiveEntry>> {
+    (0..archive.len())
+        .map(|i| {
+            let file = archive.by_index_raw(i).with_context(|| {
+                format!("Failed to access file at index {i} in {zip_file_name}")
+            })?;
+            Ok(ArchiveEntry {
+                name: utils::decode_zip_entry_name(file.name(), file.name_raw()).to_string(),
+                is_dir: file.is_dir(),
+                size: file.size(),
+                modified: entry_modified_time(&file),
+                mode: file.unix_mode(),
+            })
+        })
+        .collect()
+}
 
-            if file.is_dir() {
-                std::fs::create_dir_all(&output_path).with_context(|| {
-                    format!("Failed to create directory: {}", output_path.display())
-                })?;
-            } else {
-                if let Some(p) = output_path.parent()
-                    && !p.exists()
-                {
-                    std::fs::create_dir_all(p).with_context(|| {
-                        format!("Failed to create parent directory: {}", p.display())
-                    })?;
-                }
-                if output_path.exists() && !overwrite {
-                    continue;
-                }
-                let mut output_file = std::fs::File::create(&output_path).with_context(|| {
-                    format!("Failed to create output file: {}", output_path.display())
-                })?;
-                std::io::copy(&mut file, &mut output_file).with_context(|| {
-                    format!(
-                        "Failed to copy data to output file: {}",
-                        output_path.display()
-                    )
-                })?;
-                extracted_files += 1;
-            }
-        }
-        progress_bar.finish();
-        trash::delete(&zip_path).context("Failed to move zip file to trash")?;
-        Ok(extracted_files)
+/// Check the declared archive contents against the extraction limits before writing anything.
+/// The archive file size is used as the compressed size.
+fn check_limits(
+    path: &Path,
+    archive_name: &str,
+    entries: &[ArchiveEntry],
+    limits: &ExtractLimits,
+) -> anyhow::Result<()> {
+    limits.check_entry_count(archive_name, entries.len())?;
+    let mut uncompressed: u64 = 0;
+    for entry in entries {
+        limits.check_depth(archive_name, &entry.name)?;
+        uncompressed = uncompressed.saturating_add(entry.size);
+    }
+    let compressed = std::fs::metadata(path)
+        .with_context(|| format!("Failed to read archive size: {}", path.display()))?
+        .len();
+    limits.check_sizes(archive_name, uncompressed, compressed)?;
+    Ok(())
+}
+
+/// Classify the zip entry,
+/// reading the start of the entry data if the file extension is not known.
+fn zip_entry_kind(archive: &mut ZipArchive<std::fs::File>, index: usize, name: &str) -> EntryKind {
+    classify::classify_by_extension(name).unwrap_or_else(|| {
+        let mut prefix = Vec::new();
+        let read = archive.by_index(index).ok().and_then(|file| {
+            file.take(classify::CONTENT_PREFIX_LENGTH as u64)
+                .read_to_end(&mut prefix)
+                .ok()
+        });
+        classify::classify(name, read.map(|_| prefix.as_slice()))
     })
-    .await?
+}
+
+/// Write the given zip entries to their staged paths.
+fn extract_zip_entries(
+    archive: &mut ZipArchive<std::fs::File>,
+    entries: &[StagedEntry],
+    progress_bar: &ProgressBar,
+    budget: &SizeBudget,
+) -> anyhow::Result<()> {
+    for staged in entries {
+        let mut file = archive
+            .by_index(staged.index)
+            .with_context(|| format!("Failed to access file: {}", staged.entry.name))?;
+        let mut output_file = entry::create_file(staged)?;
+        std::io::copy(
+            &mut progress_bar.wrap_read(budget.reader(&mut file)),
+            &mut output_file,
+        )
+        .map_err(|e| {
+            limits::copy_error(
+                e,
+                format!(
+                    "Failed to copy data to output file: {}",
+                    staged.output_path.display()
+                ),
+            )
+        })?;
+        entry::finish_file(staged, &output_file)?;
+    }
+    Ok(())
+}
+
+/// Distribute entries to the given number of workers with roughly equal total size.
+fn split_entries(mut entries: Vec<StagedEntry>, workers: usize) -> Vec<Vec<StagedEntry>> {
+    entries.sort_by_key(|staged| std::cmp::Reverse(staged.entry.size));
+    let mut buckets: Vec<(u64, Vec<StagedEntry>)> = (0..workers).map(|_| (0, Vec::new())).collect();
+    for staged in entries {
+        let (total, bucket) = buckets
+            .iter_mut()
+            .min_by_key(|(total, _)| *total)
+            .expect("At least one worker");
+        *total += staged.entry.size;
+        bucket.push(staged);
+    }
+    buckets
+        .into_iter()
+        .map(|(_, bucket)| bucket)
+        .filter(|bucket| !bucket.is_empty())
+        .collect()
 }
 
 /// Download a single file with its own progress bar.
+/// The output path is claimed once the downloads before it in the input have claimed theirs.
 async fn download_file(
     client: &Client,
     dir: &Path,
     url: &str,
+    claim_place: Place,
     multi_progress: Arc<MultiProgress>,
-    overwrite: bool,
-) -> anyhow::Result<PathBuf> {
-    let response = client.get(url).send().await?;
-    if !response.status().is_success() {
-        anyhow::bail!(
-            "Request failed with status {} for: {url}",
-            response.status()
-        )
-    }
+    options: &DownloadOptions,
+) -> anyhow::Result<Downloaded> {
+    let mut response = send_request(client, url).await?;
     let headers = response.headers();
     let total_bytes = response
         .content_length()
         .unwrap_or_else(|| get_content_length_bytes(headers));
     let mut filename =
         get_filename(headers).with_context(|| format!("Failed to get filename for: {url}"))?;
+    let last_modified = if options.remote_time {
+        get_last_modified(headers)
+    } else {
+        None
+    };
 
     // Bandcamp file extensions are always in lowercase
     #[allow(clippy::case_sensitive_file_extension_comparisons)]
@@ -230,36 +723,219 @@ async fn download_file(
         // -> ".aif"
         filename.pop();
     }
+    let original_filename = filename;
+    let filename = options.filenames.apply(&original_filename);
 
-    let path = dir.join(&filename);
-    if path.exists() {
-        if !overwrite {
-            return Err(anyhow!("File already exists: {filename}"));
+    claim_place.wait().await;
+    let path = options.names.claim(dir.join(&filename), url);
+    drop(claim_place);
+    let filename = utils::get_filename_from_path(&path)?;
+
+    let progress_bar = download_progress_bar(&multi_progress, total_bytes, &filename)?;
+    options.events.emit(&Event::Started {
+        url,
+        filename: &filename,
+        total_bytes,
+    });
+
+    if let Some(extract_options) = &options.stream_extract
+        && path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"))
+    {
+        let extract_options = ExtractOptions {
+            cancel: options.cancel.clone(),
+            ..extract_options.clone()
+        };
+        let progress_events = options.events.progress(url, total_bytes);
+        match stream_extract_zip(
+            response,
+            &path,
+            &progress_bar,
+            progress_events,
+            &extract_options,
+        )
+        .await
+        {
+            Ok(extracted) => {
+                progress_bar.finish();
+                let extracted =
+                    extract_nested_archives(extracted, multi_progress, &extract_options).await;
+                return Ok(Downloaded::Extracted {
+                    archive: path,
+                    extracted,
+                });
+            }
+            Err(e) if e.is::<stream::RandomAccessRequired>() => {
+                // Start over and save the archive to disk for normal extraction
+                progress_bar.set_position(0);
+                progress_bar.set_message(format!("{filename} (saving archive: {e})"));
+                options.events.emit(&Event::Retry {
+                    url,
+                    reason: &e.to_string(),
+                });
+                response = send_request(client, url).await?;
+            }
+            Err(e) => {
+                progress_bar.abandon();
+                return Err(e);
+            }
+        }
+    }
+
+    let overwritten = path.exists();
+    if overwritten {
+        if !options.overwrite {
+            progress_bar.finish_and_clear();
+            return Ok(Downloaded::Existing(path));
         }
         tokio::fs::remove_file(&path).await?;
     }
 
-    let file = tokio::fs::File::create(&path).await?;
+    let mut progress_events = options.events.progress(url, total_bytes);
+    let file = save_response(
+        response,
+        &path,
+        &progress_bar,
+        &mut progress_events,
+        options,
+    )
+    .await?;
+    progress_bar.finish();
+    progress_events.finish();
+
+    if let Some(modified) = last_modified {
+        file.set_modified(modified)
+            .with_context(|| format!("Failed to set modification time: {}", path.display()))?;
+    }
+
+    if options.filenames.transliterate && !original_filename.is_ascii() {
+        utils::record_original_name(dir, Path::new(&filename), &original_filename)?;
+    }
+
+    Ok(Downloaded::File { path, overwritten })
+}
+
+/// Write the response body to the file.
+/// Stops and removes the file if the downloads are cancelled.
+async fn save_response(
+    response: reqwest::Response,
+    path: &Path,
+    progress_bar: &ProgressBar,
+    progress_events: &mut ProgressEvents,
+    options: &DownloadOptions,
+) -> anyhow::Result<std::fs::File> {
+    let file = tokio::fs::File::create(path).await?;
     let mut writer = BufWriter::new(file);
     let mut content = response.bytes_stream();
+    while let Some(chunk) = content.next().await {
+        if options.cancel.is_cancelled() {
+            drop(writer);
+            progress_bar.abandon();
+            tokio::fs::remove_file(path).await?;
+            return Err(Error::new(Cancelled));
+        }
+        let chunk = chunk?;
+        progress_bar.inc(chunk.len() as u64);
+        progress_events.inc(chunk.len() as u64);
+        writer.write_all(&chunk).await?;
+    }
+    writer.flush().await?;
+    Ok(writer.into_inner().into_std().await)
+}
 
+/// Add a download progress bar showing the filename.
+fn download_progress_bar(
+    multi_progress: &MultiProgress,
+    total_bytes: u64,
+    filename: &str,
+) -> anyhow::Result<ProgressBar> {
     let progress_bar = multi_progress.add(ProgressBar::new(total_bytes));
     progress_bar.set_style(
         ProgressStyle::default_bar()
             .template(PROGRESS_BAR_DOWNLOAD_TEMPLATE)?
             .progress_chars(PROGRESS_BAR_CHARS),
     );
-    progress_bar.set_message(filename.clone());
+    progress_bar.set_message(filename.to_string());
+    Ok(progress_bar)
+}
 
-    while let Some(chunk) = content.next().await {
-        let chunk = chunk?;
-        progress_bar.inc(chunk.len() as u64);
-        writer.write_all(&chunk).await?;
-    }
-    writer.flush().await?;
-    progress_bar.finish();
+/// Send GET request and check the response status.
+async fn send_request(client: &Client, url: &str) -> anyhow::Result<reqwest::Response> {
+    let response = client.get(url).send().await?;
+    let status = response.status();
+    // Keep the HTTP error in the error chain so the failure can be categorized
+    response
+        .error_for_status()
+        .with_context(|| format!("Request failed with status {status} for: {url}"))
+}
 
-    Ok(path)
+/// Extract the zip archive from the response body while it is being downloaded.
+/// Returns the extracted files.
+async fn stream_extract_zip(
+    response: reqwest::Response,
+    archive_path: &Path,
+    progress_bar: &ProgressBar,
+    mut progress_events: ProgressEvents,
+    options: &ExtractOptions,
+) -> anyhow::Result<ExtractedFiles> {
+    let extract_to = archive_path
+        .parent()
+        .context("Failed to get parent dir")?
+        .to_path_buf();
+    let zip_file_name = utils::get_filename_from_path(archive_path)?;
+    let progress = progress_bar.clone();
+    let content = response
+        .bytes_stream()
+        .inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                progress.inc(chunk.len() as u64);
+                progress_events.inc(chunk.len() as u64);
+            }
+        })
+        .map(|chunk| chunk.map_err(std::io::Error::other))
+        .boxed();
+    let reader = SyncIoBridge::new(StreamReader::new(content));
+    let options = options.clone();
+    // Use spawn_blocking to avoid blocking the async runtime
+    tokio::task::spawn_blocking(move || {
+        stream::extract_zip_stream(reader, &zip_file_name, &extract_to, &options)
+    })
+    .await?
+}
+
+/// Send the events for a finished download.
+fn emit_download_events(events: &EventSink, download: &DownloadResult) {
+    let url = &download.url;
+    let duration = download.duration;
+    match &download.result {
+        Ok(Downloaded::File { path, .. }) => events.emit(&Event::Finished {
+            url,
+            path,
+            existing: false,
+            duration,
+        }),
+        Ok(Downloaded::Existing(path)) => events.emit(&Event::Finished {
+            url,
+            path,
+            existing: true,
+            duration,
+        }),
+        Ok(Downloaded::Extracted { archive, extracted }) => {
+            events.emit(&Event::Finished {
+                url,
+                path: archive,
+                existing: false,
+                duration,
+            });
+            events.emit(&Event::Extracted { archive, extracted });
+        }
+        Err(error) => events.emit(&Event::Failed {
+            url: Some(url),
+            archive: None,
+            error,
+        }),
+    }
 }
 
 /// Get total file size from headers.
@@ -272,6 +948,57 @@ fn get_content_length_bytes(headers: &HeaderMap) -> u64 {
         .unwrap_or(0)
 }
 
+/// Get file modification time from the `Last-Modified` header.
+fn get_last_modified(headers: &HeaderMap) -> Option<SystemTime> {
+    headers
+        .get(LAST_MODIFIED)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| httpdate::parse_http_date(s).ok())
+}
+
+/// Get the last modified time for a zip entry.
+///
+/// Prefers the UTC timestamps from the extended timestamp and NTFS extra fields,
+/// and falls back to the MS-DOS timestamp, which is stored in local time.
+fn entry_modified_time<R: std::io::Read>(entry: &ZipFile<R>) -> Option<SystemTime> {
+    for field in entry.extra_data_fields() {
+        match field {
+            ExtraField::ExtendedTimestamp(timestamp) => {
+                if let Some(seconds) = timestamp.mod_time() {
+                    return Some(UNIX_EPOCH + Duration::from_secs(u64::from(seconds)));
+                }
+            }
+            ExtraField::Ntfs(ntfs) => {
+                // 100 nanosecond intervals since 1601-01-01
+                const WINDOWS_TO_UNIX_EPOCH_SECONDS: u64 = 11_644_473_600;
+                let seconds =
+                    (ntfs.mtime() / 10_000_000).checked_sub(WINDOWS_TO_UNIX_EPOCH_SECONDS);
+                if let Some(seconds) = seconds {
+                    return Some(UNIX_EPOCH + Duration::from_secs(seconds));
+                }
+            }
+        }
+    }
+
+    zip_datetime_to_system_time(entry.last_modified()?)
+}
+
+/// Convert MS-DOS timestamp in local time to system time.
+pub(crate) fn zip_datetime_to_system_time(modified: zip::DateTime) -> Option<SystemTime> {
+    let datetime = jiff::civil::DateTime::new(
+        i16::try_from(modified.year()).ok()?,
+        i8::try_from(modified.month()).ok()?,
+        i8::try_from(modified.day()).ok()?,
+        i8::try_from(modified.hour()).ok()?,
+        i8::try_from(modified.minute()).ok()?,
+        i8::try_from(modified.second()).ok()?,
+        0,
+    )
+    .ok()?;
+    let zoned = datetime.to_zoned(jiff::tz::TimeZone::system()).ok()?;
+    Some(SystemTime::from(zoned.timestamp()))
+}
+
 /// Get full filename from headers.
 fn get_filename(headers: &HeaderMap) -> anyhow::Result<String> {
     headers
@@ -288,3 +1015,139 @@ fn get_filename(headers: &HeaderMap) -> anyhow::Result<String> {
 fn create_semaphore_for_num_physical_cpus() -> Arc<Semaphore> {
     Arc::new(Semaphore::new(num_cpus::get_physical()))
 }
+
+#[cfg(test)]
+mod tests {
+    use zip::write::SimpleFileOptions;
+
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    fn planned(index: usize, size: u64) -> StagedEntry {
+        StagedEntry {
+            index,
+            entry: ArchiveEntry {
+                name: index.to_string(),
+                is_dir: false,
+                size,
+                modified: None,
+                mode: None,
+            },
+            output_path: PathBuf::from(index.to_string()),
+            staged_path: PathBuf::from(index.to_string()),
+        }
+    }
+
+    fn bucket_sizes(buckets: &[Vec<StagedEntry>]) -> Vec<u64> {
+        buckets
+            .iter()
+            .map(|bucket| bucket.iter().map(|staged| staged.entry.size).sum())
+            .collect()
+    }
+
+    #[test]
+    fn split_uneven_entries() {
+        let entries = [10, 7, 5, 3, 1].into_iter().enumerate();
+        let buckets = split_entries(entries.map(|(i, size)| planned(i, size)).collect(), 2);
+        assert_eq!(bucket_sizes(&buckets), vec![13, 13]);
+        let mut indices: Vec<usize> = buckets.iter().flatten().map(|e| e.index).collect();
+        indices.sort_unstable();
+        assert_eq!(indices, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn split_with_more_workers_than_entries() {
+        let buckets = split_entries(vec![planned(0, 1), planned(1, 2), planned(2, 0)], 5);
+        assert_eq!(buckets.len(), 3);
+        assert!(buckets.iter().all(|bucket| bucket.len() == 1));
+        assert!(split_entries(Vec::new(), 3).is_empty());
+    }
+
+    #[tokio::test]
+    async fn extract_nested_archives_from_stream() {
+        let dir = TempDir::new("stream-nested");
+        let zip = |entries: &[(&str, &[u8])]| {
+            let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+            for (name, data) in entries {
+                writer
+                    .start_file(*name, SimpleFileOptions::default())
+                    .expect("start file");
+                std::io::Write::write_all(&mut writer, data).expect("write entry");
+            }
+            writer.finish().expect("finish zip").into_inner()
+        };
+        let bonus = zip(&[("Bonus - 01 Demo.flac", b"fLaC")]);
+        let album = zip(&[("01 Track.flac", b"fLaC"), ("Bonus.zip", &bonus)]);
+
+        let options = ExtractOptions {
+            nested_depth: 1,
+            nested_disposition: ArchiveDisposition::Delete,
+            ..ExtractOptions::default()
+        };
+        let extracted = stream::extract_zip_stream(album.as_slice(), "album.zip", &dir, &options)
+            .expect("extract stream");
+        let multi_progress = Arc::new(MultiProgress::with_draw_target(
+            indicatif::ProgressDrawTarget::hidden(),
+        ));
+        let mut extracted = extract_nested_archives(extracted, multi_progress, &options).await;
+        extracted.files.sort();
+        assert_eq!(
+            extracted.files,
+            [dir.join("01 Track.flac"), dir.join("Bonus - 01 Demo.flac")]
+        );
+        assert!(extracted.nested_errors.is_empty());
+        assert!(!dir.join("Bonus.zip").exists());
+    }
+
+    #[tokio::test]
+    async fn extract_restores_entry_metadata() {
+        let dir = TempDir::new("zip-metadata");
+        let dir_time = zip::DateTime::from_date_and_time(2019, 5, 6, 7, 8, 10).expect("date");
+        let file_time = zip::DateTime::from_date_and_time(2020, 1, 2, 3, 4, 6).expect("date");
+        let mut zip =
+            zip::ZipWriter::new(std::fs::File::create(dir.join("album.zip")).expect("create zip"));
+        zip.add_directory(
+            "Album/",
+            SimpleFileOptions::default().last_modified_time(dir_time),
+        )
+        .expect("add directory");
+        zip.start_file(
+            "Album/01 Track.flac",
+            SimpleFileOptions::default()
+                .last_modified_time(file_time)
+                .unix_permissions(0o640),
+        )
+        .expect("start file");
+        std::io::Write::write_all(&mut zip, b"fLaC").expect("write entry");
+        zip.finish().expect("finish zip");
+
+        let multi_progress = Arc::new(MultiProgress::with_draw_target(
+            indicatif::ProgressDrawTarget::hidden(),
+        ));
+        let extracted = extract_archive_file(
+            dir.join("album.zip"),
+            multi_progress,
+            ExtractOptions::default(),
+        )
+        .await
+        .expect("extract zip");
+        let track = dir.join("Album").join("01 Track.flac");
+        assert_eq!(extracted.files, vec![track.clone()]);
+
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        assert_eq!(modified(&track), zip_datetime_to_system_time(file_time));
+        assert_eq!(
+            modified(&dir.join("Album")),
+            zip_datetime_to_system_time(dir_time)
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&track)
+                .expect("metadata")
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o640);
+        }
+    }
+}
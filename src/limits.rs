@@ -0,0 +1,308 @@
+use std::fmt;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use indicatif::HumanBytes;
+
+use crate::{CancelToken, Cancelled};
+
+/// Default maximum total uncompressed size of a single archive.
+pub const DEFAULT_MAX_TOTAL_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+
+/// Default maximum ratio of uncompressed to compressed size of a single archive.
+pub const DEFAULT_MAX_RATIO: u64 = 100;
+
+/// Default maximum number of entries in a single archive.
+pub const DEFAULT_MAX_ENTRIES: usize = 10_000;
+
+/// Default maximum directory nesting depth of entry paths.
+pub const DEFAULT_MAX_DEPTH: usize = 16;
+
+/// Archives with a smaller uncompressed size are not checked for compression ratio,
+/// since small text files can compress very well.
+const RATIO_CHECK_MIN_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Resource limits for extracting a single archive,
+/// to protect against zip bombs and other malicious archives.
+/// `None` disables the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtractLimits {
+    /// Maximum total uncompressed size in bytes
+    pub max_total_size: Option<u64>,
+    /// Maximum ratio of uncompressed to compressed size
+    pub max_ratio: Option<u64>,
+    /// Maximum number of entries
+    pub max_entries: Option<usize>,
+    /// Maximum directory nesting depth of entry paths
+    pub max_depth: Option<usize>,
+}
+
+/// The archive exceeds one of the extraction limits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitExceeded(pub String);
+
+/// Shared budget for the number of bytes that can still be written from an archive.
+///
+/// Enforces the total size limit on the actual decompressed data,
+/// since the sizes declared in the archive can not be trusted.
+/// Reading also stops with [`Cancelled`] once the run is cancelled.
+#[derive(Debug)]
+pub struct SizeBudget {
+    archive: String,
+    limit: Option<u64>,
+    written: AtomicU64,
+    cancel: CancelToken,
+}
+
+/// Reader that consumes the size budget for all data read through it.
+pub struct BudgetReader<'a, R> {
+    inner: R,
+    budget: &'a SizeBudget,
+}
+
+impl Default for ExtractLimits {
+    fn default() -> Self {
+        Self {
+            max_total_size: Some(DEFAULT_MAX_TOTAL_SIZE),
+            max_ratio: Some(DEFAULT_MAX_RATIO),
+            max_entries: Some(DEFAULT_MAX_ENTRIES),
+            max_depth: Some(DEFAULT_MAX_DEPTH),
+        }
+    }
+}
+
+impl ExtractLimits {
+    /// Limits with every check disabled.
+    #[must_use]
+    pub const fn unlimited() -> Self {
+        Self {
+            max_total_size: None,
+            max_ratio: None,
+            max_entries: None,
+            max_depth: None,
+        }
+    }
+
+    /// Get limits from optional command line values.
+    /// Uses the default for values that are not given, and zero disables the limit.
+    #[must_use]
+    pub fn from_options(
+        max_total_size: Option<u64>,
+        max_ratio: Option<u64>,
+        max_entries: Option<usize>,
+        max_depth: Option<usize>,
+    ) -> Self {
+        let defaults = Self::default();
+        Self {
+            max_total_size: resolve_limit(max_total_size, defaults.max_total_size),
+            max_ratio: resolve_limit(max_ratio, defaults.max_ratio),
+            max_entries: resolve_limit(max_entries, defaults.max_entries),
+            max_depth: resolve_limit(max_depth, defaults.max_depth),
+        }
+    }
+
+    /// Check the number of entries in the archive.
+    pub fn check_entry_count(&self, archive: &str, count: usize) -> Result<(), LimitExceeded> {
+        match self.max_entries {
+            Some(max) if count > max => Err(LimitExceeded(format!(
+                "{archive} has {count} entries, limit is {max}"
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the directory nesting depth of the entry path.
+    pub fn check_depth(&self, archive: &str, entry_name: &str) -> Result<(), LimitExceeded> {
+        let depth = Path::new(entry_name).components().count();
+        match self.max_depth {
+            Some(max) if depth > max => Err(LimitExceeded(format!(
+                "{archive} entry {entry_name} has nesting depth {depth}, limit is {max}"
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the declared total sizes of the archive contents.
+    pub fn check_sizes(
+        &self,
+        archive: &str,
+        uncompressed: u64,
+        compressed: u64,
+    ) -> Result<(), LimitExceeded> {
+        if let Some(max) = self.max_total_size
+            && uncompressed > max
+        {
+            return Err(LimitExceeded(format!(
+                "{archive} uncompressed size {} exceeds limit of {}",
+                HumanBytes(uncompressed),
+                HumanBytes(max)
+            )));
+        }
+        if let Some(max) = self.max_ratio
+            && uncompressed >= RATIO_CHECK_MIN_BYTES
+        {
+            let ratio = uncompressed / compressed.max(1);
+            if ratio > max {
+                return Err(LimitExceeded(format!(
+                    "{archive} compression ratio {ratio} exceeds limit of {max}"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Create a size budget for writing the contents of a single archive.
+    #[must_use]
+    pub fn size_budget(&self, archive: &str, cancel: &CancelToken) -> SizeBudget {
+        SizeBudget {
+            archive: archive.to_string(),
+            limit: self.max_total_size,
+            written: AtomicU64::new(0),
+            cancel: cancel.clone(),
+        }
+    }
+}
+
+/// Resolve a limit from an optional command line value.
+/// Uses the default when the value is not given, and zero disables the limit.
+pub fn resolve_limit<T: Default + PartialEq>(value: Option<T>, default: Option<T>) -> Option<T> {
+    match value {
+        None => default,
+        Some(value) if value == T::default() => None,
+        Some(value) => Some(value),
+    }
+}
+
+impl SizeBudget {
+    /// Wrap the reader to count its data against the budget.
+    pub const fn reader<R: Read>(&self, inner: R) -> BudgetReader<'_, R> {
+        BudgetReader {
+            inner,
+            budget: self,
+        }
+    }
+
+    /// Record written bytes.
+    /// Fails if the total goes over the limit.
+    pub fn consume(&self, bytes: u64) -> Result<(), LimitExceeded> {
+        let written = self.written.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        match self.limit {
+            Some(limit) if written > limit => Err(LimitExceeded(format!(
+                "{} uncompressed data exceeds limit of {}",
+                self.archive,
+                HumanBytes(limit)
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<R: Read> Read for BudgetReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.budget.cancel.is_cancelled() {
+            return Err(io::Error::other(Cancelled));
+        }
+        let count = self.inner.read(buf)?;
+        self.budget
+            .consume(count as u64)
+            .map_err(io::Error::other)?;
+        Ok(count)
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Archive exceeds extraction limit: {}", self.0)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// Convert an IO error from copying entry data,
+/// keeping a [`LimitExceeded`] or [`Cancelled`] error from the budget reader
+/// as the top-level error.
+pub(crate) fn copy_error(error: io::Error, context: String) -> anyhow::Error {
+    let error = match error.downcast::<LimitExceeded>() {
+        Ok(limit) => return limit.into(),
+        Err(error) => error,
+    };
+    match error.downcast::<Cancelled>() {
+        Ok(cancelled) => cancelled.into(),
+        Err(error) => anyhow::Error::new(error).context(context),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn declared_limits() {
+        let limits = ExtractLimits {
+            max_total_size: Some(1000),
+            max_ratio: Some(10),
+            max_entries: Some(2),
+            max_depth: Some(2),
+        };
+        assert!(limits.check_entry_count("a.zip", 2).is_ok());
+        assert!(limits.check_entry_count("a.zip", 3).is_err());
+        assert!(limits.check_depth("a.zip", "Album/01.flac").is_ok());
+        assert!(limits.check_depth("a.zip", "a/b/01.flac").is_err());
+        assert!(limits.check_sizes("a.zip", 1000, 1).is_ok());
+        assert!(limits.check_sizes("a.zip", 1001, 1000).is_err());
+
+        let ratio = ExtractLimits {
+            max_ratio: Some(10),
+            ..ExtractLimits::unlimited()
+        };
+        assert!(
+            ratio
+                .check_sizes("a.zip", RATIO_CHECK_MIN_BYTES, RATIO_CHECK_MIN_BYTES / 10)
+                .is_ok()
+        );
+        assert!(
+            ratio
+                .check_sizes("a.zip", RATIO_CHECK_MIN_BYTES, RATIO_CHECK_MIN_BYTES / 20)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn budget_reader_stops_at_limit() {
+        let limits = ExtractLimits {
+            max_total_size: Some(10),
+            ..ExtractLimits::unlimited()
+        };
+        let cancel = CancelToken::default();
+        let budget = limits.size_budget("a.zip", &cancel);
+        let mut output = Vec::new();
+        assert!(io::copy(&mut budget.reader([1u8; 6].as_slice()), &mut output).is_ok());
+        let error = io::copy(&mut budget.reader([1u8; 6].as_slice()), &mut output)
+            .expect_err("over the limit");
+        assert!(error.downcast::<LimitExceeded>().is_ok());
+    }
+
+    #[test]
+    fn budget_reader_stops_when_cancelled() {
+        let cancel = CancelToken::default();
+        let budget = ExtractLimits::unlimited().size_budget("a.zip", &cancel);
+        cancel.cancel();
+        let error = io::copy(&mut budget.reader([1u8; 6].as_slice()), &mut io::sink())
+            .expect_err("cancelled");
+        assert!(copy_error(error, "Failed to copy".to_string()).is::<Cancelled>());
+    }
+
+    #[test]
+    fn resolve_limit_values() {
+        assert_eq!(resolve_limit(None, Some(5)), Some(5));
+        assert_eq!(resolve_limit(Some(0), Some(5)), None);
+        assert_eq!(resolve_limit(Some(7), Some(5)), Some(7));
+
+        let limits = ExtractLimits::from_options(Some(0), None, Some(3), None);
+        assert_eq!(limits.max_total_size, None);
+        assert_eq!(limits.max_ratio, Some(DEFAULT_MAX_RATIO));
+        assert_eq!(limits.max_entries, Some(3));
+        assert_eq!(limits.max_depth, Some(DEFAULT_MAX_DEPTH));
+    }
+}
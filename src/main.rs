@@ -1,34 +1,189 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
+use anyhow::Context;
 use clap::Parser;
 use colored::Colorize;
 
-use bandcamp_dl::utils;
+use bandcamp_dl::archive::ArchiveDisposition;
+use bandcamp_dl::artwork;
+use bandcamp_dl::collision::{self, NameRegistry};
+use bandcamp_dl::config;
+use bandcamp_dl::events::{Event, EventFormat, EventSink};
+use bandcamp_dl::filter::{self, EntryFilter};
+use bandcamp_dl::history::{self, History, HistoryRecord};
+use bandcamp_dl::limits::ExtractLimits;
+use bandcamp_dl::manifest::{self, ManifestEntry, Outcome};
+use bandcamp_dl::metadata::Metadata;
+use bandcamp_dl::rename::{self, Rename, RenameTemplate, Renamer};
+use bandcamp_dl::report::{self, FileCounts, RunStatus};
+use bandcamp_dl::staging;
+use bandcamp_dl::template::{self, OutputTemplate};
+use bandcamp_dl::utils::{self, FilenameOptions, SanitizeProfile, UnicodeForm};
+use bandcamp_dl::{
+    CancelToken, Cancelled, DownloadOptions, DownloadResult, Downloaded, ExtractOptions,
+};
+
+/// Filename prefix for the list of failed URLs
+const FAILED_URLS_PREFIX: &str = "bcdl-failed-";
 
 #[derive(Parser)]
 #[command(author, about, version)]
 struct Args {
     /// A single URL or JSON string array of URLs
-    urls: String,
+    #[arg(required_unless_present = "undo_rename")]
+    urls: Option<String>,
+
+    /// Embed cover image into audio files before removing images
+    #[arg(short, long)]
+    embed_cover: bool,
+
+    /// Write lifecycle events to stdout in this format instead of showing progress bars
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    events: Option<EventFormat>,
+
+    /// Write the events to this open file descriptor instead of stdout, only on Unix
+    #[arg(long, value_name = "FD", requires = "events")]
+    events_fd: Option<u32>,
+
+    /// Exclude archive entries matching the glob pattern, can be given multiple times
+    #[arg(short = 'x', long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Move booklets, videos and other non-audio extras to this directory,
+    /// relative to the extraction directory, or an absolute path for a central directory
+    #[arg(long, value_name = "DIR")]
+    extras: Option<PathBuf>,
+
+    /// Stop all downloads and extractions after the first failure
+    #[arg(long)]
+    fail_fast: bool,
 
     /// Overwrite existing files
     #[arg(short, long)]
     force: bool,
 
+    /// Download history database path, defaults to the user data directory
+    #[arg(long, value_name = "PATH")]
+    history: Option<PathBuf>,
+
+    /// Only extract archive entries matching the glob pattern, can be given multiple times
+    #[arg(short, long, value_name = "PATTERN")]
+    include: Vec<String>,
+
+    /// Number of parallel workers per archive for large archives
+    #[arg(short, long, default_value_t = 1, value_name = "COUNT")]
+    jobs: usize,
+
+    /// Write a record of each URL to this file, as CSV for a .csv extension and JSON otherwise
+    #[arg(long, value_name = "FILE")]
+    manifest: Option<PathBuf>,
+
+    /// Maximum nesting depth of archive entry paths, 0 for no limit
+    #[arg(long, value_name = "DEPTH")]
+    max_depth: Option<usize>,
+
+    /// Maximum number of entries per archive, 0 for no limit
+    #[arg(long, value_name = "COUNT")]
+    max_entries: Option<usize>,
+
+    /// Maximum compression ratio per archive, 0 for no limit
+    #[arg(long, value_name = "RATIO")]
+    max_ratio: Option<u64>,
+
+    /// Maximum total uncompressed size per archive, for example 20G, 0 for no limit
+    #[arg(long, value_name = "SIZE", value_parser = utils::parse_size)]
+    max_size: Option<u64>,
+
+    /// What to do with archives inside archives after extracting them
+    #[arg(long, value_enum, default_value_t, value_name = "ACTION")]
+    nested_archives: ArchiveDisposition,
+
+    /// Maximum depth for extracting archives inside archives, 0 to keep them as they are
+    #[arg(long, default_value_t = 0, value_name = "DEPTH")]
+    nested_depth: usize,
+
+    /// Unicode normalization form for filenames
+    #[arg(short, long, value_enum, value_name = "FORM")]
+    normalize: Option<UnicodeForm>,
+
     /// Optional output directory
     #[arg(short, long, name = "PATH")]
     output: Option<String>,
 
+    /// Move each item to a subdirectory of the output directory,
+    /// for example "{date:%Y-%m}/{artist} - {album}".
+    /// Fields: artist, album, title, disc, track, format, year, label, date
+    #[arg(long, value_name = "TEMPLATE")]
+    output_template: Option<OutputTemplate>,
+
+    /// Use option defaults from this profile in the config file
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Download items again even if they are in the download history
+    #[arg(long)]
+    redownload: bool,
+
+    /// Set file modification time from the server for downloaded files
+    #[arg(long)]
+    remote_time: bool,
+
+    /// Rename audio files with a filename template, keeping the extension,
+    /// for example "{artist} - {title}". Has the same fields as the output template
+    #[arg(long, value_name = "TEMPLATE")]
+    rename: Option<RenameTemplate>,
+
+    /// Print the new filenames without renaming the files
+    #[arg(long, requires = "rename")]
+    rename_preview: bool,
+
+    /// Filename sanitization profile
+    #[arg(short, long, value_enum, default_value_t, value_name = "PROFILE")]
+    sanitize: SanitizeProfile,
+
+    /// Extract zip files while downloading without saving the archive
+    #[arg(long)]
+    stream: bool,
+
+    /// Transliterate non-ASCII filenames to ASCII
+    #[arg(short, long)]
+    transliterate: bool,
+
+    /// Revert the renames from a rename log and exit
+    #[arg(long, value_name = "LOG", conflicts_with = "rename")]
+    undo_rename: Option<PathBuf>,
+
     /// Verbose output
     #[arg(short, long)]
     verbose: bool,
 }
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
-    let urls = parse_urls(&args.urls)?;
+async fn main() -> anyhow::Result<ExitCode> {
+    let mut args: Args = config::parse_args()?;
+    if let Some(log) = &args.undo_rename {
+        let result = rename::undo_renames(log)?;
+        rename::print_undo(&result);
+        return Ok(RunStatus::from_counts(result.reverted.len(), result.skipped.len()).into());
+    }
+    let events = event_sink(&args)?;
+    // Normal output would get mixed with the events on stdout
+    let quiet = events.is_enabled() && args.events_fd.is_none();
+    args.verbose &= !quiet;
+    let urls = parse_urls(args.urls.as_deref().unwrap_or_default())?;
     let output_path = utils::resolve_output_path(args.output.as_deref())?;
+    remove_leftovers([output_path.as_path()], quiet);
+    let history = open_history(args.history.as_deref())?;
+    let mut entries = Vec::new();
+    let urls = skip_downloaded(urls, &history, &args, quiet, &mut entries)?;
+    if urls.is_empty() {
+        if !quiet {
+            println!("{}", "All items have already been downloaded".yellow());
+        }
+        write_manifest(args.manifest.as_deref(), &entries)?;
+        return Ok(ExitCode::SUCCESS);
+    }
 
     if args.verbose {
         println!(
@@ -43,56 +198,524 @@ async fn main() -> anyhow::Result<()> {
         );
     }
 
-    let results = match bandcamp_dl::download_urls(urls, &output_path, args.force).await {
+    let filenames = FilenameOptions {
+        sanitize: args.sanitize,
+        transliterate: args.transliterate,
+        normalize: args.normalize,
+    };
+    let names = NameRegistry::new();
+    let extract_options = extract_options(&args, filenames, &names, &events)?;
+    let download_options = DownloadOptions {
+        overwrite: args.force,
+        filenames,
+        names: names.clone(),
+        remote_time: args.remote_time,
+        stream_extract: args.stream.then(|| extract_options.clone()),
+        events: events.clone(),
+        fail_fast: args.fail_fast,
+        cancel: CancelToken::default(),
+    };
+    let results = match bandcamp_dl::download_urls(urls, &output_path, &download_options).await {
         Ok(r) => r,
         Err(e) => {
             anyhow::bail!("{e}")
         }
     };
 
+    let successful = add_download_results(results, args.verbose, &mut entries)?;
+    let zip_files = utils::get_all_archives(&successful);
+    if args.fail_fast && entries.iter().any(ManifestEntry::is_failure) {
+        cancel_archives(&zip_files, &mut entries);
+    } else if !zip_files.is_empty() {
+        extract_archives(zip_files, &extract_options, quiet, &mut entries).await;
+    }
+    let extracted_files = entries.iter().flat_map(|entry| &entry.extracted_files);
+    remove_leftovers(extracted_files.filter_map(|file| file.parent()), quiet);
+
+    if args.embed_cover {
+        embed_cover_art(&entries, args.verbose, quiet);
+    }
+
+    let removed_images = utils::remove_images(&output_path, args.verbose)?;
+    for path in &removed_images {
+        events.emit(&Event::ImageRemoved { path });
+    }
+    assign_removed_images(&mut entries, &removed_images);
+    if let Some(template) = &args.output_template {
+        apply_output_template(&mut entries, template, &output_path, filenames, &args)?;
+    }
+    if let Some(template) = &args.rename {
+        rename_files(
+            &mut entries,
+            template,
+            &output_path,
+            filenames,
+            &args,
+            quiet,
+        )?;
+    }
+    for record in entries.iter().filter_map(history_record) {
+        history.record(&record)?;
+    }
+    if !quiet {
+        collision::print_collisions(&names.collisions());
+        filter::print_skipped(&extract_options.filter);
+        print_summary(&entries, args.verbose);
+    }
+
+    write_manifest(args.manifest.as_deref(), &entries)?;
+    write_failed_urls(&entries, &args, &output_path, quiet)?;
+    let failed = entries.iter().filter(|entry| entry.is_failure()).count();
+    Ok(RunStatus::from_counts(entries.len() - failed, failed).into())
+}
+
+/// Remove leftover staging directories of interrupted runs from the directories.
+fn remove_leftovers<'a>(dirs: impl IntoIterator<Item = &'a Path>, quiet: bool) {
+    let removed = staging::remove_leftovers_in(dirs);
+    if !quiet {
+        staging::print_removed_leftovers(&removed);
+    }
+}
+
+/// Embed the cover art of each extracted archive into its audio files.
+fn embed_cover_art(entries: &[ManifestEntry], verbose: bool, quiet: bool) {
+    for entry in entries
+        .iter()
+        .filter(|entry| entry.outcome == Outcome::Extracted)
+    {
+        let result = artwork::embed_cover_art(&entry.extracted_files);
+        if !quiet {
+            artwork::print_embedded(&result, verbose);
+        }
+    }
+}
+
+/// Get archive extraction options from the arguments.
+fn extract_options(
+    args: &Args,
+    filenames: FilenameOptions,
+    names: &NameRegistry,
+    events: &EventSink,
+) -> anyhow::Result<ExtractOptions> {
+    Ok(ExtractOptions {
+        overwrite: args.force,
+        filenames,
+        names: names.clone(),
+        entry_jobs: args.jobs,
+        limits: ExtractLimits::from_options(
+            args.max_size,
+            args.max_ratio,
+            args.max_entries,
+            args.max_depth,
+        ),
+        filter: EntryFilter::new(&args.include, &args.exclude)?,
+        extras_dir: args.extras.clone(),
+        nested_depth: args.nested_depth,
+        nested_disposition: args.nested_archives,
+        events: events.clone(),
+        fail_fast: args.fail_fast,
+        cancel: CancelToken::default(),
+    })
+}
+
+/// Get the event output from the arguments.
+fn event_sink(args: &Args) -> anyhow::Result<EventSink> {
+    match (args.events, args.events_fd) {
+        (None, _) => Ok(EventSink::default()),
+        (Some(EventFormat::Ndjson), None) => Ok(EventSink::stdout()),
+        (Some(EventFormat::Ndjson), Some(fd)) => EventSink::file_descriptor(fd),
+    }
+}
+
+/// Open the download history database from the given path or the default location.
+fn open_history(path: Option<&Path>) -> anyhow::Result<History> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => history::default_path()
+            .context("Failed to get user data directory for the download history")?,
+    };
+    History::open(&path)
+}
+
+/// Remove URLs that are already in the download history, unless downloading again.
+/// Adds a skipped manifest entry for each removed URL.
+fn skip_downloaded(
+    urls: Vec<String>,
+    history: &History,
+    args: &Args,
+    quiet: bool,
+    entries: &mut Vec<ManifestEntry>,
+) -> anyhow::Result<Vec<String>> {
+    if args.redownload {
+        return Ok(urls);
+    }
+    let mut remaining = Vec::with_capacity(urls.len());
+    let mut skipped = 0;
+    for url in urls {
+        if history.contains(&url)? {
+            if args.verbose {
+                println!("Already downloaded: {}", history::url_id(&url));
+            }
+            skipped += 1;
+            entries.push(ManifestEntry::new(url, Outcome::Skipped));
+        } else {
+            remaining.push(url);
+        }
+    }
+    match skipped {
+        _ if quiet => {}
+        0 => {}
+        1 => println!("Skipping 1 item already in the download history"),
+        _ => println!("Skipping {skipped} items already in the download history"),
+    }
+    Ok(remaining)
+}
+
+/// Add a manifest entry for each download result.
+/// Returns the downloaded files.
+fn add_download_results(
+    results: Vec<DownloadResult>,
+    verbose: bool,
+    entries: &mut Vec<ManifestEntry>,
+) -> anyhow::Result<Vec<PathBuf>> {
     let mut successful: Vec<PathBuf> = Vec::new();
-    for result in results {
-        match result {
-            Ok(path) => successful.push(path),
-            Err(e) => eprintln!("{}", format!("Error: {e}").red()),
+    for download in results {
+        let mut entry = ManifestEntry::new(download.url, Outcome::Downloaded);
+        entry.duration = download.duration;
+        match download.result {
+            Ok(Downloaded::File { path, overwritten }) => {
+                add_file_info(&mut entry, &path)?;
+                entry.overwritten = usize::from(overwritten);
+                if !overwritten {
+                    entry.new_files.push(path.clone());
+                }
+                successful.push(path);
+            }
+            Ok(Downloaded::Existing(path)) => {
+                if verbose {
+                    println!("File already exists: {}", path.display());
+                }
+                entry.outcome = Outcome::Existing;
+                entry.filename = Some(utils::get_filename_from_path(&path)?);
+                entry.path = Some(path);
+                entry.skipped_existing = 1;
+            }
+            Ok(Downloaded::Extracted { archive, extracted }) => {
+                entry.filename = Some(utils::get_filename_from_path(&archive)?);
+                entry.path = Some(archive);
+                entry.set_extracted(extracted);
+            }
+            Err(e) => {
+                if !e.is::<Cancelled>() {
+                    eprintln!("{}", format!("Error: {e}").red());
+                }
+                entry.fail(&e);
+            }
         }
+        entries.push(entry);
     }
+    Ok(successful)
+}
 
-    let zip_files = utils::get_all_zip_files(&successful);
-    // Files downloaded directly (single tracks) are output as-is,
-    // zips are unpacked below.
-    let downloaded_file_count = successful.len() - zip_files.len();
+/// Add the name, size and hash of the downloaded file to the manifest entry.
+/// The hash is calculated before the file is extracted and moved to the trash.
+fn add_file_info(entry: &mut ManifestEntry, path: &Path) -> anyhow::Result<()> {
+    let size = std::fs::metadata(path)
+        .with_context(|| format!("Failed to read file metadata: {}", path.display()))?
+        .len();
+    entry.filename = Some(utils::get_filename_from_path(path)?);
+    entry.path = Some(path.to_path_buf());
+    entry.size = Some(size);
+    entry.sha256 = Some(history::file_sha256(path)?);
+    Ok(())
+}
+
+/// Extract the downloaded archives and add the extraction results to their manifest entries.
+async fn extract_archives(
+    zip_files: Vec<PathBuf>,
+    options: &ExtractOptions,
+    quiet: bool,
+    entries: &mut [ManifestEntry],
+) {
+    match zip_files.len() {
+        _ if quiet => {}
+        1 => println!("Extracting 1 archive"),
+        count => println!("Extracting {count} archives"),
+    }
+    for report in bandcamp_dl::extract_archives(zip_files, options).await {
+        let entry = entries
+            .iter_mut()
+            .find(|entry| entry.path.as_ref() == Some(&report.archive));
+        match report.result {
+            Ok(extracted) => {
+                extracted.print_nested_errors();
+                if let Some(entry) = entry {
+                    entry.set_extracted(extracted);
+                }
+            }
+            Err(e) => {
+                if !e.is::<Cancelled>() {
+                    eprintln!("{}", format!("Error: {e:#}").red());
+                }
+                if let Some(entry) = entry {
+                    entry.fail(&e);
+                }
+            }
+        }
+    }
+}
+
+/// Mark the downloaded archives as cancelled without extracting them.
+/// The archives are kept and not saved to the download history,
+/// so they are downloaded and extracted again on the next run.
+fn cancel_archives(zip_files: &[PathBuf], entries: &mut [ManifestEntry]) {
+    let cancelled = anyhow::Error::new(Cancelled);
+    for entry in entries.iter_mut().filter(|entry| {
+        entry
+            .path
+            .as_ref()
+            .is_some_and(|path| zip_files.contains(path))
+    }) {
+        entry.fail(&cancelled);
+    }
+}
+
+/// Get the download history record for a manifest entry.
+/// Returns `None` if nothing was downloaded.
+fn history_record(entry: &ManifestEntry) -> Option<HistoryRecord> {
+    let extraction = match entry.outcome {
+        Outcome::Downloaded => None,
+        Outcome::Extracted => Some(extraction_summary(entry.extracted_files.len())),
+        // The download succeeded but extracting the archive failed
+        Outcome::Failed if entry.path.is_some() => {
+            entry.error.as_ref().map(|error| format!("Error: {error}"))
+        }
+        Outcome::Failed | Outcome::Cancelled | Outcome::Existing | Outcome::Skipped => {
+            return None;
+        }
+    };
+    Some(HistoryRecord {
+        url: entry.url.clone(),
+        filename: entry.filename.clone().unwrap_or_default(),
+        size: entry.size,
+        sha256: entry.sha256.clone(),
+        extraction,
+    })
+}
+
+/// Add the removed images to the manifest entries of the downloads they came from.
+fn assign_removed_images(entries: &mut [ManifestEntry], removed_images: &[PathBuf]) {
+    for entry in entries {
+        entry.removed_images = removed_images
+            .iter()
+            .filter(|image| {
+                entry.path.as_ref() == Some(image) || entry.extracted_files.contains(image)
+            })
+            .cloned()
+            .collect();
+    }
+}
+
+/// Print the file counts of each manifest entry and the total.
+fn print_summary(entries: &[ManifestEntry], verbose: bool) {
+    let rows: Vec<(String, FileCounts)> = entries
+        .iter()
+        .map(|entry| {
+            let name = entry.filename.clone().unwrap_or_else(|| entry.url.clone());
+            (name, entry.counts())
+        })
+        .collect();
+    report::print_summary(&rows, verbose);
+
+    match entries
+        .iter()
+        .filter(|entry| entry.outcome == Outcome::Cancelled)
+        .count()
+    {
+        0 => {}
+        1 => println!("{}", "Cancelled 1 item after the first failure".yellow()),
+        count => println!(
+            "{}",
+            format!("Cancelled {count} items after the first failure").yellow()
+        ),
+    }
+}
 
-    let mut extracted_file_count = 0;
-    if !zip_files.is_empty() {
-        if zip_files.len() > 1 {
-            println!("Extracting {} zip files", zip_files.len());
+/// Move the files of each downloaded item to the directory from the output template.
+fn apply_output_template(
+    entries: &mut [ManifestEntry],
+    template: &OutputTemplate,
+    output_path: &Path,
+    filenames: FilenameOptions,
+    args: &Args,
+) -> anyhow::Result<()> {
+    let now = jiff::Zoned::now();
+    for entry in entries {
+        let (Some(filename), Some(path)) = (&entry.filename, &entry.path) else {
+            continue;
+        };
+        let files: Vec<PathBuf> = match entry.outcome {
+            Outcome::Downloaded => vec![path.clone()],
+            Outcome::Extracted => entry.extracted_files.clone(),
+            _ => continue,
+        };
+        let metadata = Metadata::for_download(filename, &files);
+        let target = output_path.join(template.render(&metadata, &now, filenames));
+        let moved = template::move_files(&files, output_path, &target, args.force)?;
+        if args.verbose {
+            println!(
+                "Moved {} to: {}",
+                filename,
+                utils::get_relative_path_from_current_working_directory(&target).display()
+            );
+        }
+        if entry.outcome == Outcome::Downloaded {
+            entry.path = moved.into_iter().next();
         } else {
-            println!("Extracting 1 zip file");
+            entry.extracted_files = moved;
         }
-        extracted_file_count = bandcamp_dl::extract_zip_files(zip_files, args.force).await;
+    }
+    Ok(())
+}
+
+/// Rename the audio files of each downloaded item with the rename template,
+/// or only print the new names in preview mode.
+/// The renames are logged to a file in the output directory so they can be reverted.
+fn rename_files(
+    entries: &mut [ManifestEntry],
+    template: &RenameTemplate,
+    output_path: &Path,
+    filenames: FilenameOptions,
+    args: &Args,
+    quiet: bool,
+) -> anyhow::Result<()> {
+    let mut planner = Renamer::new(template.clone(), filenames);
+    let mut renames = Vec::new();
+    for entry in entries.iter() {
+        let Some(filename) = &entry.filename else {
+            continue;
+        };
+        let files: Vec<PathBuf> = match entry.outcome {
+            Outcome::Downloaded => entry.path.iter().cloned().collect(),
+            Outcome::Extracted => entry.extracted_files.clone(),
+            _ => continue,
+        };
+        renames.extend(planner.plan(&files, &Metadata::from_filename(filename)));
+    }
+    if args.rename_preview {
+        if !quiet {
+            rename::print_renames(&renames, true);
+        }
+        return Ok(());
+    }
+
+    let log = rename::new_log_path(output_path);
+    let result = rename::apply_renames(&renames, &log)?;
+    rename::print_failed_renames(&result.failed);
+    let completed = result.renamed;
+    if completed.is_empty() {
+        return Ok(());
+    }
+    for entry in entries.iter_mut() {
+        entry.path = entry
+            .path
+            .as_ref()
+            .map(|path| Rename::resolve(&completed, path));
+        for file in &mut entry.extracted_files {
+            *file = Rename::resolve(&completed, file);
+        }
+    }
+    if !quiet {
         if args.verbose {
-            println!("Unzipped {extracted_file_count} files");
+            rename::print_renames(&completed, false);
+        } else {
+            match completed.len() {
+                1 => println!("{}", "Renamed 1 file".cyan()),
+                count => println!("{}", format!("Renamed {count} files").cyan()),
+            }
         }
+        rename::print_undo_command(&log);
     }
+    Ok(())
+}
 
-    let removed_image_count = utils::remove_images(&output_path, args.verbose)?;
+/// Write the manifest file if requested.
+fn write_manifest(path: Option<&Path>, entries: &[ManifestEntry]) -> anyhow::Result<()> {
+    path.map_or(Ok(()), |path| manifest::write_manifest(path, entries))
+}
 
-    // Count only what this run produced,
-    // direct downloads plus files unpacked from zips,
-    // minus the cover images removed afterwards.
-    // This avoids miscounting from unrelated filesystem changes,
-    // or files extracted into subdirectories.
-    let added = (downloaded_file_count + extracted_file_count).saturating_sub(removed_image_count);
-    match added {
-        added if added >= 2 => println!("{}", format!("Added {added} new files").green()),
-        1 => println!("{}", "Added 1 new file".green()),
-        _ => println!("{}", "No new files added".yellow()),
+/// Write the failed URLs to a JSON file in the output directory,
+/// and print the command for downloading them again.
+fn write_failed_urls(
+    entries: &[ManifestEntry],
+    args: &Args,
+    output_path: &Path,
+    quiet: bool,
+) -> anyhow::Result<()> {
+    let failed: Vec<&ManifestEntry> = entries.iter().filter(|entry| entry.is_failure()).collect();
+    if failed.is_empty() {
+        return Ok(());
     }
+    let urls: Vec<&str> = failed.iter().map(|entry| entry.url.as_str()).collect();
+    let timestamp = jiff::Zoned::now().strftime("%Y%m%d-%H%M%S");
+    let path = output_path.join(format!("{FAILED_URLS_PREFIX}{timestamp}.json"));
+    std::fs::write(&path, serde_json::to_string_pretty(&urls)?)
+        .with_context(|| format!("Failed to write failed URLs: {}", path.display()))?;
 
+    if !quiet {
+        // Archives that failed to extract are already in the download history
+        let redownload = failed.iter().any(|entry| history_record(entry).is_some());
+        let command = retry_command(
+            std::env::args_os().map(|arg| arg.to_string_lossy().into_owned()),
+            args.urls.as_deref().unwrap_or_default(),
+            &path,
+            redownload,
+        );
+        let message = match urls.len() {
+            1 => format!("Saved 1 failed URL to: {}", path.display()),
+            count => format!("Saved {count} failed URLs to: {}", path.display()),
+        };
+        println!("{}", message.yellow());
+        println!("Retry with:\n  {command}");
+    }
     Ok(())
 }
 
+/// Build the command line for running again with the URLs read from the given file.
+fn retry_command(
+    args: impl IntoIterator<Item = String>,
+    urls: &str,
+    urls_file: &Path,
+    redownload: bool,
+) -> String {
+    let mut command: Vec<String> = args
+        .into_iter()
+        .map(|arg| {
+            if arg == urls {
+                format!(
+                    "\"$(cat {})\"",
+                    utils::shell_quote(&urls_file.to_string_lossy())
+                )
+            } else {
+                utils::shell_quote(&arg)
+            }
+        })
+        .collect();
+    if redownload && !command.iter().any(|arg| arg == "--redownload") {
+        command.push("--redownload".to_string());
+    }
+    command.join(" ")
+}
+
+/// Describe a successful archive extraction for the download history.
+fn extraction_summary(count: usize) -> String {
+    if count == 1 {
+        "Extracted 1 file".to_string()
+    } else {
+        format!("Extracted {count} files")
+    }
+}
+
 /// Parse URL input argument string to a list of URLs.
 fn parse_urls(urls: &str) -> anyhow::Result<Vec<String>> {
     let urls: Vec<String> = match serde_json::from_str(urls) {
@@ -130,7 +753,8 @@ mod test_cli {
             ]"#,
         ]);
 
-        let urls: Vec<String> = parse_urls(&args.urls).expect("Failed to parse URLs");
+        let urls: Vec<String> =
+            parse_urls(args.urls.as_deref().unwrap_or_default()).expect("Failed to parse URLs");
         assert_eq!(urls.len(), 10);
         assert_eq!(
             urls,
@@ -153,7 +777,7 @@ mod test_cli {
     fn argument_single_url_string() {
         let args = Args::parse_from(["test", r"https://p4.bcbits.com/download/album/10"]);
 
-        let urls: Vec<String> = parse_urls(&args.urls).unwrap();
+        let urls: Vec<String> = parse_urls(args.urls.as_deref().unwrap_or_default()).unwrap();
         assert_eq!(urls.len(), 1);
         assert_eq!(urls, vec!["https://p4.bcbits.com/download/album/10"]);
     }
@@ -169,7 +793,8 @@ mod test_cli {
             "--verbose",
         ]);
 
-        let urls: Vec<String> = parse_urls(&args.urls).expect("Failed to parse URLs");
+        let urls: Vec<String> =
+            parse_urls(args.urls.as_deref().unwrap_or_default()).expect("Failed to parse URLs");
         assert_eq!(
             urls,
             vec![
@@ -181,4 +806,17 @@ mod test_cli {
         assert!(args.verbose);
         assert_eq!(args.output.as_deref(), Some("output_path"));
     }
+
+    #[test]
+    fn retry_failed_command() {
+        let urls = r#"["https://p4.bcbits.com/download/track/1"]"#;
+        let args = ["bcdl", urls, "--output", "My Music", "--force"].map(String::from);
+        let file = Path::new("My Music/bcdl-failed-20260101-120000.json");
+        assert_eq!(
+            retry_command(args.clone(), urls, file, false),
+            r#"bcdl "$(cat 'My Music/bcdl-failed-20260101-120000.json')" --output 'My Music' --force"#
+        );
+        assert!(retry_command(args, urls, file, true).ends_with(" --force --redownload"));
+        assert_eq!(utils::shell_quote("it's"), r"'it'\''s'");
+    }
 }
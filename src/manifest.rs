@@ -0,0 +1,394 @@
+use std::fmt::Write as _;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use serde_json::json;
+
+use crate::Cancelled;
+use crate::limits::LimitExceeded;
+use crate::report::{self, ExtractedFiles, FileCounts};
+use crate::stream::RandomAccessRequired;
+
+const CSV_HEADER: &str = "url,filename,path,size,sha256,duration_seconds,outcome,error_category,error,overwritten,skipped_existing,extracted_files,removed_images";
+
+/// What happened to a single input URL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// File downloaded and saved as-is
+    Downloaded,
+    /// Archive downloaded and extracted
+    Extracted,
+    /// Not downloaded because the file already exists
+    Existing,
+    /// Already in the download history
+    Skipped,
+    /// Download or extraction failed
+    Failed,
+    /// Not started because an earlier item failed in fail-fast mode
+    Cancelled,
+}
+
+/// Broad category of a failure, for filtering failed items in the manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// Connection or HTTP error
+    Network,
+    /// Reading or writing local files failed
+    Io,
+    /// Invalid or unsupported archive
+    Archive,
+    /// Archive exceeds an extraction limit
+    Limit,
+    /// Not started because of an earlier failure
+    Cancelled,
+    Other,
+}
+
+/// Output format of the manifest file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestFormat {
+    Json,
+    Csv,
+}
+
+/// Manifest record for a single input URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// Input URL
+    pub url: String,
+    /// Name of the downloaded file or archive
+    pub filename: Option<String>,
+    /// Path of the downloaded file or archive
+    pub path: Option<PathBuf>,
+    /// Downloaded file size in bytes
+    pub size: Option<u64>,
+    /// SHA-256 hash of the downloaded file as a hex string
+    pub sha256: Option<String>,
+    /// Time spent downloading
+    pub duration: Duration,
+    pub outcome: Outcome,
+    pub error_category: Option<ErrorCategory>,
+    /// Error message with the full error chain
+    pub error: Option<String>,
+    /// Written files that did not replace an existing file
+    pub new_files: Vec<PathBuf>,
+    /// Number of written files that replaced an existing file
+    pub overwritten: usize,
+    /// Number of files not written because they already exist
+    pub skipped_existing: usize,
+    /// Files extracted from the archive
+    pub extracted_files: Vec<PathBuf>,
+    /// Extracted or downloaded images that were removed afterwards
+    pub removed_images: Vec<PathBuf>,
+}
+
+impl Outcome {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Downloaded => "downloaded",
+            Self::Extracted => "extracted",
+            Self::Existing => "existing",
+            Self::Skipped => "skipped",
+            Self::Failed => "failed",
+            Self::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl ErrorCategory {
+    /// Categorize the error from the types in its error chain.
+    #[must_use]
+    pub fn of(error: &anyhow::Error) -> Self {
+        let mut category = Self::Other;
+        for cause in error.chain() {
+            if cause.is::<LimitExceeded>() {
+                return Self::Limit;
+            }
+            if cause.is::<Cancelled>() {
+                return Self::Cancelled;
+            }
+            if cause.is::<reqwest::Error>() {
+                return Self::Network;
+            }
+            if cause.is::<zip::result::ZipError>()
+                || cause.is::<sevenz_rust2::Error>()
+                || cause.is::<RandomAccessRequired>()
+            {
+                return Self::Archive;
+            }
+            if cause.is::<io::Error>() {
+                category = Self::Io;
+            }
+        }
+        category
+    }
+
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Network => "network",
+            Self::Io => "io",
+            Self::Archive => "archive",
+            Self::Limit => "limit",
+            Self::Cancelled => "cancelled",
+            Self::Other => "other",
+        }
+    }
+}
+
+impl ManifestFormat {
+    /// Get the format from the file extension, using JSON unless the extension is `csv`.
+    #[must_use]
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("csv") => Self::Csv,
+            _ => Self::Json,
+        }
+    }
+}
+
+impl ManifestEntry {
+    /// Create an entry for the URL without any file information.
+    #[must_use]
+    pub const fn new(url: String, outcome: Outcome) -> Self {
+        Self {
+            url,
+            filename: None,
+            path: None,
+            size: None,
+            sha256: None,
+            duration: Duration::ZERO,
+            outcome,
+            error_category: None,
+            error: None,
+            new_files: Vec::new(),
+            overwritten: 0,
+            skipped_existing: 0,
+            extracted_files: Vec::new(),
+            removed_images: Vec::new(),
+        }
+    }
+
+    /// Get the file counts for the run summary.
+    /// Extracted archives count as downloaded, even though they are not kept.
+    #[must_use]
+    pub fn counts(&self) -> FileCounts {
+        FileCounts {
+            downloaded: usize::from(matches!(
+                self.outcome,
+                Outcome::Downloaded | Outcome::Extracted
+            )),
+            extracted: self.extracted_files.len(),
+            skipped_existing: self.skipped_existing,
+            overwritten: self.overwritten,
+            failed: usize::from(self.outcome == Outcome::Failed),
+            images_removed: self.removed_images.len(),
+            added: report::count_added(&self.new_files, &self.removed_images),
+        }
+    }
+
+    /// Add the extraction results to the entry.
+    pub fn set_extracted(&mut self, extracted: ExtractedFiles) {
+        self.outcome = Outcome::Extracted;
+        self.overwritten += extracted.overwritten;
+        self.skipped_existing += extracted.skipped_existing;
+        // The archive itself is not kept
+        self.new_files = extracted.new_files;
+        self.extracted_files = extracted.files;
+    }
+
+    /// Mark the entry as failed with the given error,
+    /// or as cancelled if the error is [`Cancelled`].
+    pub fn fail(&mut self, error: &anyhow::Error) {
+        let category = ErrorCategory::of(error);
+        self.outcome = if category == ErrorCategory::Cancelled {
+            Outcome::Cancelled
+        } else {
+            Outcome::Failed
+        };
+        self.error_category = Some(category);
+        self.error = Some(format!("{error:#}"));
+    }
+
+    /// Check if the item failed or was cancelled.
+    #[must_use]
+    pub const fn is_failure(&self) -> bool {
+        matches!(self.outcome, Outcome::Failed | Outcome::Cancelled)
+    }
+}
+
+/// Write the manifest in the format matching the file extension.
+pub fn write_manifest(path: &Path, entries: &[ManifestEntry]) -> anyhow::Result<()> {
+    let content = match ManifestFormat::from_path(path) {
+        ManifestFormat::Json => serde_json::to_string_pretty(&to_json(entries))?,
+        ManifestFormat::Csv => to_csv(entries),
+    };
+    std::fs::write(path, content)
+        .with_context(|| format!("Failed to write manifest: {}", path.display()))
+}
+
+fn to_json(entries: &[ManifestEntry]) -> serde_json::Value {
+    let paths = |paths: &[PathBuf]| -> Vec<String> {
+        paths
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect()
+    };
+    entries
+        .iter()
+        .map(|entry| {
+            json!({
+                "url": entry.url,
+                "filename": entry.filename,
+                "path": entry.path.as_ref().map(|path| path.to_string_lossy()),
+                "size": entry.size,
+                "sha256": entry.sha256,
+                "duration_seconds": entry.duration.as_secs_f64(),
+                "outcome": entry.outcome.as_str(),
+                "error_category": entry.error_category.map(ErrorCategory::as_str),
+                "error": entry.error,
+                "overwritten": entry.overwritten,
+                "skipped_existing": entry.skipped_existing,
+                "extracted_files": paths(&entry.extracted_files),
+                "removed_images": paths(&entry.removed_images),
+            })
+        })
+        .collect()
+}
+
+/// Format the entries as CSV with one row per URL.
+/// File lists are joined with semicolons.
+fn to_csv(entries: &[ManifestEntry]) -> String {
+    let join = |paths: &[PathBuf]| -> String {
+        paths
+            .iter()
+            .map(|path| path.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(";")
+    };
+    let mut csv = String::from(CSV_HEADER);
+    csv.push('\n');
+    for entry in entries {
+        let fields = [
+            entry.url.clone(),
+            entry.filename.clone().unwrap_or_default(),
+            entry
+                .path
+                .as_ref()
+                .map(|path| path.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            entry.size.map(|size| size.to_string()).unwrap_or_default(),
+            entry.sha256.clone().unwrap_or_default(),
+            format!("{:.3}", entry.duration.as_secs_f64()),
+            entry.outcome.as_str().to_string(),
+            entry
+                .error_category
+                .map(|category| category.as_str().to_string())
+                .unwrap_or_default(),
+            entry.error.clone().unwrap_or_default(),
+            entry.overwritten.to_string(),
+            entry.skipped_existing.to_string(),
+            join(&entry.extracted_files),
+            join(&entry.removed_images),
+        ];
+        let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        let _ = writeln!(csv, "{}", row.join(","));
+    }
+    csv
+}
+
+/// Quote the CSV field if needed.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<ManifestEntry> {
+        let mut extracted = ManifestEntry::new(
+            "https://p4.bcbits.com/download/album/1".to_string(),
+            Outcome::Extracted,
+        );
+        extracted.filename = Some("Artist - Album, Live.zip".to_string());
+        extracted.size = Some(100);
+        extracted.duration = Duration::from_millis(1500);
+        extracted.extracted_files = vec![PathBuf::from("01.flac"), PathBuf::from("cover.jpg")];
+        extracted.new_files = extracted.extracted_files.clone();
+        extracted.removed_images = vec![PathBuf::from("cover.jpg")];
+
+        let mut failed = ManifestEntry::new(
+            "https://p4.bcbits.com/download/album/2".to_string(),
+            Outcome::Downloaded,
+        );
+        failed.fail(&anyhow::Error::new(LimitExceeded("too big".to_string())).context("Failed"));
+        vec![extracted, failed]
+    }
+
+    #[test]
+    fn error_categories() {
+        let io_error = anyhow::Error::new(io::Error::other("disk full")).context("Failed to write");
+        assert_eq!(ErrorCategory::of(&io_error), ErrorCategory::Io);
+        let limit = anyhow::Error::new(LimitExceeded("x".to_string()));
+        assert_eq!(ErrorCategory::of(&limit), ErrorCategory::Limit);
+        assert_eq!(
+            ErrorCategory::of(&anyhow::anyhow!("File already exists")),
+            ErrorCategory::Other
+        );
+
+        let mut entry = ManifestEntry::new("url".to_string(), Outcome::Downloaded);
+        entry.fail(&anyhow::Error::new(Cancelled));
+        assert_eq!(entry.outcome, Outcome::Cancelled);
+        assert!(entry.is_failure());
+    }
+
+    #[test]
+    fn entry_counts() {
+        let entries = entries();
+        let counts = entries[0].counts();
+        assert_eq!(counts.downloaded, 1);
+        assert_eq!(counts.extracted, 2);
+        assert_eq!(counts.images_removed, 1);
+        assert_eq!(counts.added, 1);
+        assert_eq!(entries[1].counts().failed, 1);
+    }
+
+    #[test]
+    fn json_manifest() {
+        let json = to_json(&entries());
+        assert_eq!(json[0]["outcome"], "extracted");
+        assert_eq!(json[0]["duration_seconds"], 1.5);
+        assert_eq!(json[0]["extracted_files"][1], "cover.jpg");
+        assert_eq!(json[1]["outcome"], "failed");
+        assert_eq!(json[1]["error_category"], "limit");
+        assert_eq!(json[1]["path"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn csv_manifest() {
+        let csv = to_csv(&entries());
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], CSV_HEADER);
+        assert_eq!(
+            lines[1],
+            "https://p4.bcbits.com/download/album/1,\"Artist - Album, Live.zip\",,100,,1.500,extracted,,,0,0,01.flac;cover.jpg,cover.jpg"
+        );
+        assert!(lines[2].contains(",failed,limit,"));
+        assert_eq!(
+            ManifestFormat::from_path(Path::new("run.CSV")),
+            ManifestFormat::Csv
+        );
+        assert_eq!(
+            ManifestFormat::from_path(Path::new("run.json")),
+            ManifestFormat::Json
+        );
+    }
+}
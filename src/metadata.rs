@@ -0,0 +1,129 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use lofty::file::TaggedFileExt;
+use lofty::tag::{Accessor, ItemKey};
+
+use crate::bandcamp;
+use crate::classify::{self, EntryKind};
+
+/// Release and track information for output templates.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub title: Option<String>,
+    pub disc: Option<u32>,
+    pub track: Option<u32>,
+    /// Audio file format as the lowercase file extension, for example `flac`
+    pub format: Option<String>,
+    pub year: Option<u32>,
+    pub label: Option<String>,
+}
+
+impl Metadata {
+    /// Get the metadata for a download from its filename and the tags of its first audio file.
+    /// Values from the filename are preferred since the tags are often incomplete.
+    #[must_use]
+    pub fn for_download(filename: &str, files: &[PathBuf]) -> Self {
+        let audio = files.iter().find(|file| {
+            classify::classify_by_extension(&file.to_string_lossy()) == Some(EntryKind::Audio)
+        });
+        let mut metadata = Self::from_filename(filename);
+        if let Some(audio) = audio {
+            metadata.format = audio
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_lowercase());
+            if let Ok(tags) = Self::from_tags(audio) {
+                metadata.fill(tags);
+            }
+        }
+        metadata
+    }
+
+    /// Get the metadata from a Bandcamp filename.
+    /// See [`bandcamp::parse_filename`] for the supported naming conventions.
+    #[must_use]
+    pub fn from_filename(filename: &str) -> Self {
+        let parsed = bandcamp::parse_filename(filename);
+        Self {
+            artist: parsed.artist,
+            album: parsed.album,
+            title: parsed.title,
+            disc: parsed.disc,
+            track: parsed.track,
+            ..Self::default()
+        }
+    }
+
+    /// Read the metadata from the audio file tags.
+    pub fn from_tags(path: &Path) -> anyhow::Result<Self> {
+        let tagged_file = lofty::read_from_path(path)
+            .with_context(|| format!("Failed to read tags: {}", path.display()))?;
+        let Some(tag) = tagged_file
+            .primary_tag()
+            .or_else(|| tagged_file.first_tag())
+        else {
+            return Ok(Self::default());
+        };
+        Ok(Self {
+            artist: tag.artist().map(String::from),
+            album: tag.album().map(String::from),
+            title: tag.title().map(String::from),
+            disc: tag.disk(),
+            track: tag.track(),
+            format: None,
+            year: tag.date().map(|date| u32::from(date.year)),
+            label: tag
+                .get_string(ItemKey::Label)
+                .or_else(|| tag.get_string(ItemKey::Publisher))
+                .map(String::from),
+        })
+    }
+
+    /// Add the values missing from this metadata.
+    pub fn fill(&mut self, other: Self) {
+        self.artist = self.artist.take().or(other.artist);
+        self.album = self.album.take().or(other.album);
+        self.title = self.title.take().or(other.title);
+        self.disc = self.disc.or(other.disc);
+        self.track = self.track.or(other.track);
+        self.format = self.format.take().or(other.format);
+        self.year = self.year.or(other.year);
+        self.label = self.label.take().or(other.label);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_from_filename() {
+        let album = Metadata::from_filename("Artist - Album.zip");
+        assert_eq!(album.artist.as_deref(), Some("Artist"));
+        assert_eq!(album.album.as_deref(), Some("Album"));
+        assert_eq!(album.track, None);
+
+        let track = Metadata::from_filename("Artist - Album - 03 Title.flac");
+        assert_eq!(track.album.as_deref(), Some("Album"));
+        assert_eq!(track.track, Some(3));
+        assert_eq!(track.title.as_deref(), Some("Title"));
+
+        let track = Metadata::from_filename("Artist - Album - 2-01 Song - Remix.flac");
+        assert_eq!(track.disc, Some(2));
+        assert_eq!(track.title.as_deref(), Some("Song - Remix"));
+    }
+
+    #[test]
+    fn fill_missing_values() {
+        let mut metadata = Metadata::from_filename("Artist - Album.zip");
+        metadata.fill(Metadata {
+            artist: Some("Tag Artist".to_string()),
+            year: Some(2024),
+            ..Metadata::default()
+        });
+        assert_eq!(metadata.artist.as_deref(), Some("Artist"));
+        assert_eq!(metadata.year, Some(2024));
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::watch;
+
+/// Lets concurrent tasks pass a section one at a time in input order,
+/// so shared state like claimed output paths does not depend on timing.
+#[derive(Debug, Clone)]
+pub struct InputOrder {
+    state: Arc<OrderState>,
+}
+
+#[derive(Debug)]
+struct OrderState {
+    /// Index of the first task that has not passed yet
+    next: watch::Sender<usize>,
+    /// Tasks that passed while an earlier task had not
+    passed: Mutex<BTreeSet<usize>>,
+}
+
+/// The place of a task in the input order.
+/// Dropping it marks the task as passed, also if it never waited for its turn.
+#[derive(Debug)]
+pub struct Place {
+    state: Arc<OrderState>,
+    index: usize,
+}
+
+impl InputOrder {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(OrderState {
+                next: watch::Sender::new(0),
+                passed: Mutex::new(BTreeSet::new()),
+            }),
+        }
+    }
+
+    /// Get the place of the task with the given index.
+    /// Every index from zero needs a place, or later tasks wait forever.
+    pub fn place(&self, index: usize) -> Place {
+        Place {
+            state: Arc::clone(&self.state),
+            index,
+        }
+    }
+}
+
+impl Place {
+    /// Wait until all earlier tasks have passed.
+    pub async fn wait(&self) {
+        let mut next = self.state.next.subscribe();
+        next.wait_for(|next| *next >= self.index)
+            .await
+            .expect("Input order sender dropped");
+    }
+}
+
+impl Drop for Place {
+    fn drop(&mut self) {
+        let mut passed = self.state.passed.lock().expect("Input order lock poisoned");
+        passed.insert(self.index);
+        self.state.next.send_modify(|next| {
+            while passed.remove(next) {
+                *next += 1;
+            }
+        });
+        drop(passed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn tasks_pass_in_input_order() {
+        let order = InputOrder::new();
+        let passed = Arc::new(Mutex::new(Vec::new()));
+        let tasks: Vec<_> = (0..4)
+            .map(|index| {
+                let place = order.place(index);
+                let passed = Arc::clone(&passed);
+                tokio::spawn(async move {
+                    // Later tasks are ready first
+                    tokio::time::sleep(Duration::from_millis(40 - 10 * index as u64)).await;
+                    if index == 1 {
+                        // Leaving without waiting still lets later tasks pass
+                        return;
+                    }
+                    place.wait().await;
+                    passed.lock().expect("lock").push(index);
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.expect("task");
+        }
+        assert_eq!(*passed.lock().expect("lock"), vec![0, 2, 3]);
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::Context;
+use colored::Colorize;
+use serde_json::json;
+
+use crate::classify::{self, EntryKind};
+use crate::metadata::Metadata;
+use crate::template::OutputTemplate;
+use crate::utils::{self, FilenameOptions};
+
+/// Filename prefix for rename logs.
+pub const RENAME_LOG_PREFIX: &str = "bcdl-renames-";
+
+/// Filename template for audio files without the extension, for example `{artist} - {title}`.
+/// Has the same fields as the output template, and the file extension is kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenameTemplate {
+    template: OutputTemplate,
+}
+
+/// A planned or completed file rename.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rename {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+/// Plans new names for audio files with a rename template,
+/// keeping track of the names used during the run.
+#[derive(Debug)]
+pub struct Renamer {
+    template: RenameTemplate,
+    filenames: FilenameOptions,
+    now: jiff::Zoned,
+    claimed: HashSet<String>,
+}
+
+/// Result of applying renames.
+#[derive(Debug, Default)]
+pub struct RenameResult {
+    pub renamed: Vec<Rename>,
+    /// Renames that failed, with the error message
+    pub failed: Vec<(Rename, String)>,
+}
+
+/// Result of reverting the renames from a rename log.
+#[derive(Debug, Default)]
+pub struct UndoResult {
+    pub reverted: Vec<Rename>,
+    /// Renames that could not be reverted since the file was moved or the old name is taken
+    pub skipped: Vec<Rename>,
+}
+
+impl FromStr for RenameTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        if template.contains(['/', '\\']) {
+            anyhow::bail!("Rename template must be a filename without directories: {template}");
+        }
+        if template.trim().is_empty() {
+            anyhow::bail!("Rename template can not be empty");
+        }
+        Ok(Self {
+            template: template.parse()?,
+        })
+    }
+}
+
+impl Renamer {
+    #[must_use]
+    pub fn new(template: RenameTemplate, filenames: FilenameOptions) -> Self {
+        Self {
+            template,
+            filenames,
+            now: jiff::Zoned::now(),
+            claimed: HashSet::new(),
+        }
+    }
+
+    /// Plan renames for the audio files of a download.
+    ///
+    /// Values come from the filename of each audio file, then its tags,
+    /// and then the download metadata.
+    /// Files that already have the new name are left out,
+    /// and names taken by existing files or earlier renames get a number suffix.
+    pub fn plan(&mut self, files: &[PathBuf], download: &Metadata) -> Vec<Rename> {
+        let mut renames = Vec::new();
+        for file in files {
+            let name = utils::get_filename_from_path(file).unwrap_or_default();
+            if classify::classify_by_extension(&name) != Some(EntryKind::Audio) || !file.is_file() {
+                continue;
+            }
+            let mut metadata = Metadata::from_filename(&name);
+            if let Ok(tags) = Metadata::from_tags(file) {
+                metadata.fill(tags);
+            }
+            metadata.fill(download.clone());
+            let extension = file
+                .extension()
+                .map(|extension| extension.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            metadata.format = Some(extension.to_lowercase());
+
+            let stem = self
+                .template
+                .template
+                .render_filename(&metadata, &self.now, self.filenames);
+            let to = self.claim(file, &stem, &extension);
+            if to != *file {
+                renames.push(Rename {
+                    from: file.clone(),
+                    to,
+                });
+            }
+        }
+        renames
+    }
+
+    /// Claim a free path for the new name in the same directory as the file.
+    fn claim(&mut self, file: &Path, stem: &str, extension: &str) -> PathBuf {
+        let mut counter = 1;
+        loop {
+            let name = match counter {
+                1 => format!("{stem}.{extension}"),
+                _ => format!("{stem} ({counter}).{extension}"),
+            };
+            let path = file.with_file_name(name);
+            let key = path.to_string_lossy().to_lowercase();
+            if !self.claimed.contains(&key) && (path == file || !path.exists()) {
+                self.claimed.insert(key);
+                return path;
+            }
+            counter += 1;
+        }
+    }
+}
+
+impl Rename {
+    /// Get the new path of the file if it was renamed.
+    #[must_use]
+    pub fn resolve(renames: &[Self], path: &Path) -> PathBuf {
+        renames
+            .iter()
+            .find(|rename| rename.from == path)
+            .map_or_else(|| path.to_path_buf(), |rename| rename.to.clone())
+    }
+}
+
+/// Path for a new rename log in the given directory.
+#[must_use]
+pub fn new_log_path(dir: &Path) -> PathBuf {
+    let timestamp = jiff::Zoned::now().strftime("%Y%m%d-%H%M%S");
+    dir.join(format!("{RENAME_LOG_PREFIX}{timestamp}.jsonl"))
+}
+
+/// Rename the files and append each rename to the log file as a JSON line.
+///
+/// The renames can be reverted with [`undo_renames`].
+/// Files that fail to rename are left in place.
+/// Returns the completed and the failed renames.
+pub fn apply_renames(renames: &[Rename], log: &Path) -> anyhow::Result<RenameResult> {
+    let mut result = RenameResult::default();
+    if renames.is_empty() {
+        return Ok(result);
+    }
+    let mut log_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log)
+        .with_context(|| format!("Failed to open rename log: {}", log.display()))?;
+    for rename in renames {
+        if let Err(error) = fs::rename(&rename.from, &rename.to) {
+            result.failed.push((rename.clone(), error.to_string()));
+            continue;
+        }
+        let line = json!({
+            "from": rename.from.to_string_lossy(),
+            "to": rename.to.to_string_lossy(),
+            "timestamp": jiff::Timestamp::now().to_string(),
+        });
+        writeln!(log_file, "{line}")
+            .with_context(|| format!("Failed to write rename log: {}", log.display()))?;
+        result.renamed.push(rename.clone());
+    }
+    Ok(result)
+}
+
+/// Revert the renames from a rename log, newest first.
+/// Files that were moved since, or whose old name is taken, are left in place.
+pub fn undo_renames(log: &Path) -> anyhow::Result<UndoResult> {
+    let content = fs::read_to_string(log)
+        .with_context(|| format!("Failed to read rename log: {}", log.display()))?;
+    let mut renames = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: serde_json::Value = serde_json::from_str(line)
+            .with_context(|| format!("Invalid rename log line {}: {}", index + 1, log.display()))?;
+        let (Some(from), Some(to)) = (value["from"].as_str(), value["to"].as_str()) else {
+            anyhow::bail!("Invalid rename log line {}: {}", index + 1, log.display());
+        };
+        renames.push(Rename {
+            from: PathBuf::from(from),
+            to: PathBuf::from(to),
+        });
+    }
+
+    let mut result = UndoResult::default();
+    for rename in renames.into_iter().rev() {
+        if rename.to.is_file()
+            && !rename.from.exists()
+            && fs::rename(&rename.to, &rename.from).is_ok()
+        {
+            result.reverted.push(rename);
+        } else {
+            result.skipped.push(rename);
+        }
+    }
+    Ok(result)
+}
+
+/// Print the renamed files, or the planned renames in preview mode.
+pub fn print_renames(renames: &[Rename], preview: bool) {
+    let heading = match (renames.len(), preview) {
+        (0, true) => "No files to rename".to_string(),
+        (1, true) => "Would rename 1 file:".to_string(),
+        (count, true) => format!("Would rename {count} files:"),
+        (1, false) => "Renamed 1 file:".to_string(),
+        (count, false) => format!("Renamed {count} files:"),
+    };
+    println!("{}", heading.cyan());
+    for rename in renames {
+        println!(
+            "  {} -> {}",
+            utils::get_relative_path_from_current_working_directory(&rename.from).display(),
+            rename.to.file_name().unwrap_or_default().to_string_lossy()
+        );
+    }
+}
+
+/// Print the files that could not be renamed.
+pub fn print_failed_renames(failed: &[(Rename, String)]) {
+    for (rename, error) in failed {
+        eprintln!(
+            "{}",
+            format!("Error: Failed to rename {}: {error}", rename.from.display()).red()
+        );
+    }
+}
+
+/// Print the command for reverting the renames in the log.
+pub fn print_undo_command(log: &Path) {
+    let program = std::env::args().next().unwrap_or_default();
+    println!(
+        "Undo with:\n  {} --undo-rename {}",
+        utils::shell_quote(&program),
+        utils::shell_quote(&log.to_string_lossy())
+    );
+}
+
+/// Print the reverted renames and the renames that could not be reverted.
+pub fn print_undo(result: &UndoResult) {
+    match result.reverted.len() {
+        1 => println!("{}", "Reverted 1 rename".green()),
+        count => println!("{}", format!("Reverted {count} renames").green()),
+    }
+    for rename in &result.skipped {
+        println!(
+            "{}",
+            format!(
+                "Could not revert: {} -> {}",
+                rename.to.display(),
+                rename.from.display()
+            )
+            .yellow()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn invalid_templates() {
+        assert!("{artist}/{title}".parse::<RenameTemplate>().is_err());
+        assert!("{genre}".parse::<RenameTemplate>().is_err());
+        assert!(" ".parse::<RenameTemplate>().is_err());
+        assert!("{artist} - {title}".parse::<RenameTemplate>().is_ok());
+    }
+
+    #[test]
+    fn plan_renames() {
+        let dir = TempDir::new("plan");
+        let files = [
+            dir.join("Artist - Album - 01 Title.aif"),
+            dir.join("Artist - Album - 02 Title.aif"),
+            dir.join("cover.jpg"),
+        ];
+        for file in &files {
+            fs::write(file, b"data").expect("write file");
+        }
+        fs::write(dir.join("Artist - Intro.aif"), b"data").expect("write file");
+
+        let template = "{artist} - {title}".parse().expect("template");
+        let mut planner = Renamer::new(template, FilenameOptions::default());
+        let renames = planner.plan(&files, &Metadata::default());
+        assert_eq!(renames.len(), 2);
+        assert_eq!(renames[0].to, dir.join("Artist - Title.aif"));
+        assert_eq!(renames[1].to, dir.join("Artist - Title (2).aif"));
+
+        let renames = planner.plan(&[dir.join("Artist - Intro.aif")], &Metadata::default());
+        assert!(renames.is_empty());
+    }
+
+    #[test]
+    fn apply_and_undo_renames() {
+        let dir = TempDir::new("undo");
+        let files = [
+            dir.join("Artist - Album - 01 One.flac"),
+            dir.join("Artist - Album - 02 Two.flac"),
+        ];
+        for file in &files {
+            fs::write(file, b"data").expect("write file");
+        }
+        let template = "{track} {title}".parse().expect("template");
+        let renames =
+            Renamer::new(template, FilenameOptions::default()).plan(&files, &Metadata::default());
+        let log = new_log_path(&dir);
+        let result = apply_renames(&renames, &log).expect("apply renames");
+        assert!(result.failed.is_empty());
+        let completed = result.renamed;
+        assert_eq!(completed.len(), 2);
+        assert!(dir.join("01 One.flac").is_file());
+        assert_eq!(
+            Rename::resolve(&completed, &files[1]),
+            dir.join("02 Two.flac")
+        );
+
+        let result = undo_renames(&log).expect("undo renames");
+        assert_eq!(result.reverted.len(), 2);
+        assert!(result.skipped.is_empty());
+        assert!(files.iter().all(|file| file.is_file()));
+    }
+}
@@ -0,0 +1,284 @@
+use std::ops::AddAssign;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use colored::Colorize;
+
+use crate::utils;
+
+/// Files written by extracting a single archive.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtractedFiles {
+    /// Extracted files, including the ones that replaced an existing file
+    pub files: Vec<PathBuf>,
+    /// Extracted files that did not replace an existing file
+    pub new_files: Vec<PathBuf>,
+    /// Number of extracted files that replaced an existing file
+    pub overwritten: usize,
+    /// Number of entries not extracted because the file already exists
+    pub skipped_existing: usize,
+    /// Nested archives that failed to extract, with the error message.
+    /// They are kept with the extracted files.
+    pub nested_errors: Vec<(PathBuf, String)>,
+}
+
+/// Result of extracting a single archive.
+#[derive(Debug)]
+pub struct ArchiveReport {
+    pub archive: PathBuf,
+    pub result: anyhow::Result<ExtractedFiles>,
+}
+
+/// Number of files handled during a run, for a single item or in total.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileCounts {
+    /// Files downloaded and saved as-is
+    pub downloaded: usize,
+    /// Files extracted from archives
+    pub extracted: usize,
+    /// Files not written because they already exist
+    pub skipped_existing: usize,
+    /// Written files that replaced an existing file
+    pub overwritten: usize,
+    /// Failed downloads and archives
+    pub failed: usize,
+    /// Images from this run that were removed afterwards
+    pub images_removed: usize,
+    /// New files that did not replace an existing file and were kept
+    pub added: usize,
+}
+
+impl ExtractedFiles {
+    /// Add the files from another extraction.
+    pub fn merge(&mut self, other: Self) {
+        self.files.extend(other.files);
+        self.new_files.extend(other.new_files);
+        self.overwritten += other.overwritten;
+        self.skipped_existing += other.skipped_existing;
+        self.nested_errors.extend(other.nested_errors);
+    }
+
+    /// Print the errors from nested archives.
+    pub fn print_nested_errors(&self) {
+        for (archive, error) in &self.nested_errors {
+            eprintln!(
+                "{}",
+                format!(
+                    "Error: Failed to extract nested archive {}: {error}",
+                    utils::get_relative_path_from_current_working_directory(archive).display()
+                )
+                .red()
+            );
+        }
+    }
+}
+
+impl ArchiveReport {
+    /// Get the file counts for the archive.
+    #[must_use]
+    pub fn counts(&self, removed_images: &[PathBuf]) -> FileCounts {
+        self.result.as_ref().map_or_else(
+            |_| FileCounts {
+                failed: 1,
+                ..FileCounts::default()
+            },
+            |extracted| FileCounts {
+                extracted: extracted.files.len(),
+                skipped_existing: extracted.skipped_existing,
+                overwritten: extracted.overwritten,
+                images_removed: removed_images
+                    .iter()
+                    .filter(|image| extracted.files.contains(image))
+                    .count(),
+                added: count_added(&extracted.new_files, removed_images),
+                ..FileCounts::default()
+            },
+        )
+    }
+}
+
+/// Count the new files that were not removed afterwards.
+#[must_use]
+pub fn count_added(new_files: &[PathBuf], removed_images: &[PathBuf]) -> usize {
+    new_files
+        .iter()
+        .filter(|file| !removed_images.contains(file))
+        .count()
+}
+
+impl AddAssign for FileCounts {
+    fn add_assign(&mut self, other: Self) {
+        self.downloaded += other.downloaded;
+        self.extracted += other.extracted;
+        self.skipped_existing += other.skipped_existing;
+        self.overwritten += other.overwritten;
+        self.failed += other.failed;
+        self.images_removed += other.images_removed;
+        self.added += other.added;
+    }
+}
+
+impl std::iter::Sum for FileCounts {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), |mut total, counts| {
+            total += counts;
+            total
+        })
+    }
+}
+
+/// Overall result of a run, used for the process exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunStatus {
+    /// Nothing failed
+    Success,
+    /// Some items failed
+    PartialFailure,
+    /// All items failed
+    TotalFailure,
+}
+
+impl RunStatus {
+    /// Get the status from the number of successful and failed items.
+    #[must_use]
+    pub const fn from_counts(succeeded: usize, failed: usize) -> Self {
+        match (succeeded, failed) {
+            (_, 0) => Self::Success,
+            (0, _) => Self::TotalFailure,
+            _ => Self::PartialFailure,
+        }
+    }
+
+    /// Process exit code for the status.
+    /// Code 2 is left out since it is used for invalid arguments.
+    #[must_use]
+    pub const fn exit_code(self) -> u8 {
+        match self {
+            Self::Success => 0,
+            Self::TotalFailure => 1,
+            Self::PartialFailure => 3,
+        }
+    }
+}
+
+impl From<RunStatus> for ExitCode {
+    fn from(status: RunStatus) -> Self {
+        Self::from(status.exit_code())
+    }
+}
+
+const SUMMARY_HEADERS: [&str; 6] = [
+    "Downloaded",
+    "Extracted",
+    "Existing",
+    "Overwritten",
+    "Failed",
+    "Images removed",
+];
+
+/// Print a table of the file counts with a row for each item and the total.
+/// The item rows are only printed with the verbose flag.
+pub fn print_summary(items: &[(String, FileCounts)], verbose: bool) {
+    let total: FileCounts = items.iter().map(|(_, counts)| *counts).sum();
+    let rows: &[(String, FileCounts)] = if verbose { items } else { &[] };
+    let name_width = rows
+        .iter()
+        .map(|(name, _)| name.chars().count())
+        .chain(std::iter::once("Total".len()))
+        .max()
+        .unwrap_or_default();
+
+    println!("{:<name_width$}  {}", "", SUMMARY_HEADERS.join("  ").bold());
+    for (name, counts) in rows {
+        println!("{name:<name_width$}  {}", format_counts(counts));
+    }
+    println!(
+        "{}",
+        format!("{:<name_width$}  {}", "Total", format_counts(&total)).bold()
+    );
+
+    match total.added {
+        added if added >= 2 => println!("{}", format!("Added {added} new files").green()),
+        1 => println!("{}", "Added 1 new file".green()),
+        _ => println!("{}", "No new files added".yellow()),
+    }
+}
+
+/// Format the counts as table columns aligned with the headers.
+fn format_counts(counts: &FileCounts) -> String {
+    [
+        counts.downloaded,
+        counts.extracted,
+        counts.skipped_existing,
+        counts.overwritten,
+        counts.failed,
+        counts.images_removed,
+    ]
+    .iter()
+    .zip(SUMMARY_HEADERS)
+    .map(|(count, header)| format!("{count:>width$}", width = header.len()))
+    .collect::<Vec<_>>()
+    .join("  ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_counts() {
+        let report = ArchiveReport {
+            archive: PathBuf::from("album.zip"),
+            result: Ok(ExtractedFiles {
+                files: vec![PathBuf::from("01.flac"), PathBuf::from("cover.jpg")],
+                new_files: vec![PathBuf::from("cover.jpg")],
+                overwritten: 1,
+                skipped_existing: 2,
+                nested_errors: Vec::new(),
+            }),
+        };
+        let removed = [PathBuf::from("cover.jpg"), PathBuf::from("other.jpg")];
+        let counts = report.counts(&removed);
+        assert_eq!(
+            counts,
+            FileCounts {
+                extracted: 2,
+                skipped_existing: 2,
+                overwritten: 1,
+                images_removed: 1,
+                added: 0,
+                ..FileCounts::default()
+            }
+        );
+
+        let failed = ArchiveReport {
+            archive: PathBuf::from("broken.zip"),
+            result: Err(anyhow::anyhow!("Invalid archive")),
+        };
+        assert_eq!(failed.counts(&removed).failed, 1);
+    }
+
+    #[test]
+    fn run_status() {
+        assert_eq!(RunStatus::from_counts(0, 0), RunStatus::Success);
+        assert_eq!(RunStatus::from_counts(5, 0), RunStatus::Success);
+        assert_eq!(RunStatus::from_counts(4, 1), RunStatus::PartialFailure);
+        assert_eq!(RunStatus::from_counts(0, 2), RunStatus::TotalFailure);
+        assert_eq!(RunStatus::PartialFailure.exit_code(), 3);
+    }
+
+    #[test]
+    fn sum_counts() {
+        let counts = FileCounts {
+            downloaded: 1,
+            extracted: 10,
+            images_removed: 1,
+            added: 9,
+            ..FileCounts::default()
+        };
+        let total: FileCounts = [counts, counts].into_iter().sum();
+        assert_eq!(total.downloaded, 2);
+        assert_eq!(total.extracted, 20);
+        assert_eq!(total.added, 18);
+    }
+}
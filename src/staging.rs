@@ -0,0 +1,442 @@
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+
+use crate::report::ExtractedFiles;
+use crate::utils;
+
+/// Name prefix of the hidden directories archives are extracted into.
+pub const STAGING_DIR_PREFIX: &str = ".bcdl-staging-";
+
+/// Minimum age of a lock file without a staging directory before it is removed,
+/// so lock files of staging directories that are being created are kept.
+const ORPHAN_LOCK_AGE: Duration = Duration::from_secs(60);
+
+/// Counter to keep staging directory names unique within the process.
+static STAGING_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Hidden directory that an archive is extracted into before the files are moved into place,
+/// so a failed extraction never leaves a partially extracted album behind.
+///
+/// The staging directory is created in the extraction directory,
+/// so the files can be renamed on the same filesystem.
+/// A lock file next to it is held while it is in use,
+/// so other runs only remove staging directories whose owner is gone.
+/// The directory and any files still in it are removed when dropped.
+#[derive(Debug)]
+pub struct Staging {
+    extract_to: PathBuf,
+    dir: PathBuf,
+    /// Lock file held until the staging directory is removed
+    lock: File,
+    /// Directories to create on commit, with their modification times
+    dirs: Vec<(PathBuf, Option<SystemTime>)>,
+    /// Staged file paths with their final paths
+    files: Vec<(PathBuf, PathBuf)>,
+    /// Number of entries not extracted because the file already exists
+    skipped_existing: usize,
+    /// Final paths of transliterated files with their original names
+    original_names: Vec<(PathBuf, String)>,
+}
+
+impl Staging {
+    /// Create a staging directory in the extraction directory.
+    pub fn create(extract_to: &Path) -> anyhow::Result<Self> {
+        let dir = extract_to.join(format!(
+            "{}{}",
+            process_prefix(),
+            STAGING_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        // The lock is taken before the directory exists, so it is never seen unlocked
+        let lock = std::fs::create_dir_all(extract_to)
+            .and_then(|()| File::create(lock_path(&dir)))
+            .and_then(|lock| lock.try_lock().map(|()| lock).map_err(io::Error::from))
+            .and_then(|lock| std::fs::create_dir(&dir).map(|()| lock))
+            .with_context(|| format!("Failed to create staging directory: {}", dir.display()))?;
+        Ok(Self {
+            extract_to: extract_to.to_path_buf(),
+            dir,
+            lock,
+            dirs: Vec::new(),
+            files: Vec::new(),
+            skipped_existing: 0,
+            original_names: Vec::new(),
+        })
+    }
+
+    /// Get the path to write a file to.
+    /// The file is moved to the final path on commit.
+    pub fn stage_file(&mut self, final_path: PathBuf) -> PathBuf {
+        let staged = self.dir.join(self.files.len().to_string());
+        self.files.push((staged.clone(), final_path));
+        staged
+    }
+
+    /// Record an entry that is not extracted because the file already exists.
+    pub const fn skip_existing(&mut self) {
+        self.skipped_existing += 1;
+    }
+
+    /// Record the original name of a staged file,
+    /// written to the original names file in the extraction directory on commit.
+    pub fn record_original_name(&mut self, final_path: PathBuf, original: &str) {
+        self.original_names.push((final_path, original.to_string()));
+    }
+
+    /// Create the directory on commit, and set its modification time once the files are in place.
+    pub fn stage_dir(&mut self, path: PathBuf, modified: Option<SystemTime>) {
+        self.dirs.push((path, modified));
+    }
+
+    /// Move all staged files to their final paths.
+    /// Replaced files are kept in the staging directory until all files are in place.
+    /// If moving fails, the files that were already moved are removed again
+    /// and the replaced files are restored.
+    /// Returns the final file paths.
+    pub fn commit(mut self) -> anyhow::Result<ExtractedFiles> {
+        for (dir, _) in &self.dirs {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create directory: {}", dir.display()))?;
+        }
+        let mut moved = Vec::with_capacity(self.files.len());
+        for (index, (staged, final_path)) in std::mem::take(&mut self.files).into_iter().enumerate()
+        {
+            match self.replace(index, &staged, &final_path) {
+                Ok(backup) => moved.push((final_path, backup)),
+                Err(error) => {
+                    roll_back(moved);
+                    return Err(error);
+                }
+            }
+        }
+        if let Err(error) = self.write_original_names() {
+            roll_back(moved);
+            return Err(error);
+        }
+        set_dir_modified_times(&mut self.dirs);
+        let new_files = moved
+            .iter()
+            .filter(|(_, backup)| backup.is_none())
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        Ok(ExtractedFiles {
+            overwritten: moved.len() - new_files.len(),
+            files: moved.into_iter().map(|(path, _)| path).collect(),
+            new_files,
+            skipped_existing: self.skipped_existing,
+            nested_errors: Vec::new(),
+        })
+    }
+}
+
+impl Staging {
+    /// Append the recorded original names to the original names file.
+    fn write_original_names(&self) -> anyhow::Result<()> {
+        for (final_path, original) in &self.original_names {
+            let name = final_path
+                .strip_prefix(&self.extract_to)
+                .unwrap_or(final_path);
+            utils::record_original_name(&self.extract_to, name, original)?;
+        }
+        Ok(())
+    }
+
+    /// Move the staged file to its final path,
+    /// moving an existing file into the staging directory first.
+    /// Returns the path of the replaced file in the staging directory.
+    fn replace(
+        &self,
+        index: usize,
+        staged: &Path,
+        final_path: &Path,
+    ) -> anyhow::Result<Option<PathBuf>> {
+        let backup = if final_path.exists() {
+            let backup = self.dir.join(format!("{index}.replaced"));
+            move_file(final_path, &backup)?;
+            Some(backup)
+        } else {
+            None
+        };
+        if let Err(error) = move_file(staged, final_path) {
+            if let Some(backup) = &backup {
+                let _ = move_file(backup, final_path);
+            }
+            return Err(error);
+        }
+        Ok(backup)
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+        // Removed before the lock is released when the file is closed
+        let _ = std::fs::remove_file(lock_path(&self.dir));
+        let _ = self.lock.unlock();
+    }
+}
+
+/// Undo a failed commit, removing the moved files and restoring the files they replaced.
+fn roll_back(moved: Vec<(PathBuf, Option<PathBuf>)>) {
+    for (path, backup) in moved.into_iter().rev() {
+        match backup {
+            Some(backup) => {
+                let _ = move_file(&backup, &path);
+            }
+            None => {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// Set the modification times of the directories, deepest first,
+/// since changing the contents of a directory updates its modification time.
+/// Failures are ignored, since directories can not be opened for writing on all platforms.
+fn set_dir_modified_times(dirs: &mut [(PathBuf, Option<SystemTime>)]) {
+    dirs.sort_by_key(|(dir, _)| std::cmp::Reverse(dir.components().count()));
+    for (dir, modified) in dirs.iter() {
+        if let Some(modified) = modified {
+            let _ = std::fs::File::open(dir).and_then(|file| file.set_modified(*modified));
+        }
+    }
+}
+
+/// Name prefix for the staging directories of this process.
+fn process_prefix() -> String {
+    format!("{STAGING_DIR_PREFIX}{}-", std::process::id())
+}
+
+/// Path of the lock file for the staging directory.
+fn lock_path(dir: &Path) -> PathBuf {
+    let mut path = OsString::from(dir.as_os_str());
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+/// Check that no running process holds the lock of the staging directory.
+/// A missing lock file means the owner is gone, since it is removed last.
+fn is_abandoned(dir: &Path) -> bool {
+    match File::open(lock_path(dir)) {
+        Ok(lock) => lock.try_lock().is_ok(),
+        Err(e) => e.kind() == io::ErrorKind::NotFound,
+    }
+}
+
+/// Check that the lock file has no staging directory and is not held or recently created.
+fn is_orphan_lock(lock: &Path) -> bool {
+    let dir = lock.with_extension("");
+    let old = std::fs::metadata(lock)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age >= ORPHAN_LOCK_AGE);
+    old && !dir.exists() && File::open(lock).is_ok_and(|file| file.try_lock().is_ok())
+}
+
+/// Remove staging directories in the directory left behind by interrupted runs.
+///
+/// Staging directories still locked by a running process are kept.
+/// Lock files left without a staging directory are removed too.
+/// Returns the removed directories.
+#[must_use]
+pub fn remove_leftovers(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let own_prefix = process_prefix();
+    let mut removed = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if !name.starts_with(STAGING_DIR_PREFIX) || name.starts_with(&own_prefix) {
+            continue;
+        }
+        let path = entry.path();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            if is_abandoned(&path) && std::fs::remove_dir_all(&path).is_ok() {
+                let _ = std::fs::remove_file(lock_path(&path));
+                removed.push(path);
+            }
+        } else if path
+            .extension()
+            .is_some_and(|extension| extension == "lock")
+            && is_orphan_lock(&path)
+        {
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+    removed
+}
+
+/// Remove leftover staging directories in each of the directories, visiting each one once.
+/// Returns the removed directories.
+#[must_use]
+pub fn remove_leftovers_in<'a>(dirs: impl IntoIterator<Item = &'a Path>) -> Vec<PathBuf> {
+    let dirs: BTreeSet<&Path> = dirs.into_iter().collect();
+    dirs.into_iter().flat_map(remove_leftovers).collect()
+}
+
+/// Print the leftover staging directories that were removed.
+pub fn print_removed_leftovers(removed: &[PathBuf]) {
+    for dir in removed {
+        println!("Removed leftover staging directory: {}", dir.display());
+    }
+}
+
+/// Move the file, creating the parent directories.
+/// Copies the file if it can not be renamed across filesystems.
+pub(crate) fn move_file(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create parent directory: {}", parent.display()))?;
+    }
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            let modified = std::fs::metadata(from).and_then(|m| m.modified());
+            std::fs::copy(from, to)
+                .with_context(|| format!("Failed to copy file to: {}", to.display()))?;
+            if let Ok(modified) = modified {
+                let _ = std::fs::File::options()
+                    .write(true)
+                    .open(to)
+                    .and_then(|file| file.set_modified(modified));
+            }
+            Ok(())
+        }
+        result => result.with_context(|| format!("Failed to move file to: {}", to.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    fn staging_dirs(dir: &Path) -> usize {
+        std::fs::read_dir(dir)
+            .expect("read dir")
+            .flatten()
+            .filter(|e| {
+                e.file_name()
+                    .to_string_lossy()
+                    .starts_with(STAGING_DIR_PREFIX)
+            })
+            .count()
+    }
+
+    #[test]
+    fn commit_moves_files_into_place() {
+        let dir = TempDir::new("commit");
+        let mut staging = Staging::create(&dir).expect("create staging");
+        let staged = staging.stage_file(dir.join("Album").join("01 Track.flac"));
+        std::fs::write(&staged, b"fLaC").expect("write staged file");
+        staging.stage_dir(dir.join("Empty"), None);
+        assert!(!dir.join("Album").exists());
+
+        let files = staging.commit().expect("commit").files;
+        assert_eq!(files, vec![dir.join("Album").join("01 Track.flac")]);
+        assert_eq!(std::fs::read(&files[0]).expect("read"), b"fLaC");
+        assert!(dir.join("Empty").is_dir());
+        assert_eq!(staging_dirs(&dir), 0);
+    }
+
+    #[test]
+    fn dropped_staging_leaves_nothing_behind() {
+        let dir = TempDir::new("drop");
+        let mut staging = Staging::create(&dir).expect("create staging");
+        let staged = staging.stage_file(dir.join("01 Track.flac"));
+        std::fs::write(&staged, b"fLaC").expect("write staged file");
+        drop(staging);
+        assert_eq!(std::fs::read_dir(&dir).expect("read dir").count(), 0);
+    }
+
+    #[test]
+    fn original_names_are_written_on_commit() {
+        let dir = TempDir::new("original-names-commit");
+        let mut staging = Staging::create(&dir).expect("create staging");
+        let staged = staging.stage_file(dir.join("Cafe.flac"));
+        staging.record_original_name(dir.join("Cafe.flac"), "Café.flac");
+        std::fs::write(&staged, b"fLaC").expect("write staged file");
+        drop(staging);
+        assert!(!dir.join(utils::ORIGINAL_NAMES_FILE).exists());
+
+        let mut staging = Staging::create(&dir).expect("create staging");
+        let staged = staging.stage_file(dir.join("Cafe.flac"));
+        staging.record_original_name(dir.join("Cafe.flac"), "Café.flac");
+        std::fs::write(&staged, b"fLaC").expect("write staged file");
+        staging.commit().expect("commit");
+        assert_eq!(
+            utils::read_original_names(&dir).expect("read names"),
+            vec![(PathBuf::from("Cafe.flac"), "Café.flac".to_string())]
+        );
+    }
+
+    #[test]
+    fn failed_commit_restores_replaced_files() {
+        let dir = TempDir::new("rollback");
+        std::fs::write(dir.join("01 Track.flac"), b"old").expect("write existing file");
+        std::fs::write(dir.join("Album"), b"not a directory").expect("write file");
+        let mut staging = Staging::create(&dir).expect("create staging");
+        let first = staging.stage_file(dir.join("01 Track.flac"));
+        std::fs::write(&first, b"new").expect("write staged file");
+        let second = staging.stage_file(dir.join("Album").join("02 Track.flac"));
+        std::fs::write(&second, b"new").expect("write staged file");
+
+        assert!(staging.commit().is_err());
+        assert_eq!(
+            std::fs::read(dir.join("01 Track.flac")).expect("read"),
+            b"old"
+        );
+        assert_eq!(staging_dirs(&dir), 0);
+    }
+
+    #[test]
+    fn only_abandoned_leftovers_are_removed() {
+        let dir = TempDir::new("leftovers");
+        let abandoned = dir.join(format!("{STAGING_DIR_PREFIX}0-0"));
+        std::fs::create_dir_all(&abandoned).expect("create leftover");
+        std::fs::write(abandoned.join("0"), b"partial").expect("write leftover file");
+        let running = dir.join(format!("{STAGING_DIR_PREFIX}0-1"));
+        std::fs::create_dir_all(&running).expect("create leftover");
+        let lock = File::create(lock_path(&running)).expect("create lock file");
+        lock.try_lock().expect("lock");
+
+        assert_eq!(remove_leftovers(&dir), vec![abandoned.clone()]);
+        assert!(!abandoned.exists());
+        assert!(running.exists());
+    }
+
+    #[test]
+    fn leftovers_near_extracted_files_are_removed() {
+        let dir = TempDir::new("leftovers-near");
+        let album = dir.join("Album");
+        let abandoned = album.join(format!("{STAGING_DIR_PREFIX}0-0"));
+        std::fs::create_dir_all(&abandoned).expect("create leftover");
+        let old = SystemTime::now() - ORPHAN_LOCK_AGE * 2;
+        let orphan_lock = album.join(format!("{STAGING_DIR_PREFIX}0-1.lock"));
+        File::create(&orphan_lock)
+            .and_then(|lock| lock.set_modified(old))
+            .expect("create orphan lock");
+        let new_lock = album.join(format!("{STAGING_DIR_PREFIX}0-2.lock"));
+        File::create(&new_lock).expect("create new lock");
+
+        let files = [album.join("01 Track.flac"), album.join("02 Track.flac")];
+        let dirs = files.iter().filter_map(|file| file.parent());
+        assert_eq!(remove_leftovers_in(dirs), vec![abandoned.clone()]);
+        assert!(!abandoned.exists());
+        assert!(!orphan_lock.exists());
+        assert!(new_lock.exists());
+    }
+}
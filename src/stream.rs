@@ -0,0 +1,556 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use flate2::bufread::DeflateDecoder;
+
+use crate::archive::ArchiveEntry;
+use crate::limits::{self, SizeBudget};
+use crate::report::ExtractedFiles;
+use crate::staging::Staging;
+use crate::{ExtractOptions, classify, entry, utils};
+
+const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const CENTRAL_DIRECTORY_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0605_4b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x0807_4b50;
+
+const FLAG_ENCRYPTED: u16 = 1;
+const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+const FLAG_UTF8: u16 = 1 << 11;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+const EXTRA_FIELD_ZIP64: u16 = 0x0001;
+const EXTRA_FIELD_EXTENDED_TIMESTAMP: u16 = 0x5455;
+const EXTRA_FIELD_UNICODE_PATH: u16 = 0x7075;
+
+/// Size values in the local file header that mean the real size is stored in the Zip64 extra field.
+const ZIP64_SIZE_MARKER: u32 = u32::MAX;
+
+const STREAM_BUFFER_SIZE: usize = 256 * 1024;
+
+/// The archive can not be extracted from a stream and needs to be saved to disk first.
+#[derive(Debug)]
+pub struct RandomAccessRequired(pub String);
+
+impl fmt::Display for RandomAccessRequired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Zip archive requires random access: {}", self.0)
+    }
+}
+
+impl std::error::Error for RandomAccessRequired {}
+
+/// Fields from a zip local file header needed for sequential extraction.
+struct LocalFileHeader {
+    flags: u16,
+    method: u16,
+    dos_time: u16,
+    dos_date: u16,
+    crc32: u32,
+    compressed_size: u64,
+    zip64: bool,
+    name: String,
+    modified: Option<SystemTime>,
+}
+
+impl LocalFileHeader {
+    /// Get the modification time from the extended timestamp, or the DOS date and time.
+    fn modified_time(&self) -> Option<SystemTime> {
+        self.modified.or_else(|| {
+            zip::DateTime::try_from((self.dos_date, self.dos_time))
+                .ok()
+                .and_then(crate::zip_datetime_to_system_time)
+        })
+    }
+}
+
+/// Extract a zip archive from a stream,
+/// reading the entries sequentially using the local file headers and data descriptors.
+///
+/// Files written before an error are removed.
+/// Fails with [`RandomAccessRequired`] for entries whose size can only be found from the central directory.
+/// The archive is then extracted again after saving it,
+/// so the paths claimed for its entries are released and its filtered entries are not counted.
+/// Returns the extracted files.
+pub fn extract_zip_stream<R: Read>(
+    reader: R,
+    zip_file_name: &str,
+    extract_to: &Path,
+    options: &ExtractOptions,
+) -> anyhow::Result<ExtractedFiles> {
+    let attempt = ExtractOptions {
+        filter: options.filter.scratch(),
+        ..options.clone()
+    };
+    let result = extract_zip_stream_attempt(reader, zip_file_name, extract_to, &attempt);
+    match &result {
+        Ok(_) => options.filter.merge(&attempt.filter),
+        Err(e) if e.is::<RandomAccessRequired>() => options
+            .names
+            .release(&entry::entry_source(zip_file_name, "")),
+        Err(_) => {}
+    }
+    result
+}
+
+fn extract_zip_stream_attempt<R: Read>(
+    reader: R,
+    zip_file_name: &str,
+    extract_to: &Path,
+    options: &ExtractOptions,
+) -> anyhow::Result<ExtractedFiles> {
+    let mut reader = BufReader::with_capacity(STREAM_BUFFER_SIZE, reader);
+    // Dropping the staging directory on error removes the partially extracted files
+    let mut staging = Staging::create(extract_to)?;
+    extract_entries(
+        &mut reader,
+        zip_file_name,
+        extract_to,
+        options,
+        &mut staging,
+    )?;
+    // Consume the central directory so the whole response is read
+    io::copy(&mut reader, &mut io::sink()).context("Failed to read end of zip stream")?;
+    staging.commit()
+}
+
+fn extract_entries<R: BufRead>(
+    reader: &mut R,
+    zip_file_name: &str,
+    extract_to: &Path,
+    options: &ExtractOptions,
+    staging: &mut Staging,
+) -> anyhow::Result<()> {
+    // The central directory comes last, so only the actual data can be checked
+    let budget = options.limits.size_budget(zip_file_name, &options.cancel);
+    let mut entry_count = 0;
+    loop {
+        match read_u32(reader)? {
+            LOCAL_FILE_HEADER_SIGNATURE => {}
+            CENTRAL_DIRECTORY_HEADER_SIGNATURE | END_OF_CENTRAL_DIRECTORY_SIGNATURE => {
+                return Ok(());
+            }
+            signature => anyhow::bail!("Invalid zip stream signature: {signature:#010x}"),
+        }
+
+        let header = read_local_file_header(reader)?;
+        entry_count += 1;
+        options
+            .limits
+            .check_entry_count(zip_file_name, entry_count)?;
+        options.limits.check_depth(zip_file_name, &header.name)?;
+        if header.flags & FLAG_ENCRYPTED != 0 {
+            return Err(RandomAccessRequired(format!("encrypted entry {}", header.name)).into());
+        }
+        let has_data_descriptor = header.flags & FLAG_DATA_DESCRIPTOR != 0;
+        let is_dir = header.name.ends_with('/');
+        if header.method != METHOD_STORED && header.method != METHOD_DEFLATED {
+            return Err(RandomAccessRequired(format!(
+                "unsupported compression method {} for {}",
+                header.method, header.name
+            ))
+            .into());
+        }
+        if header.method == METHOD_STORED
+            && has_data_descriptor
+            && header.compressed_size == 0
+            && !is_dir
+        {
+            return Err(
+                RandomAccessRequired(format!("stored entry {} without size", header.name)).into(),
+            );
+        }
+
+        let entry = ArchiveEntry {
+            name: header.name.clone(),
+            is_dir,
+            size: 0,
+            modified: header.modified_time(),
+            mode: None,
+        };
+        // Entry data can not be read ahead in a stream, so extras are only detected by extension
+        let staged = entry::stage_entry(
+            entry_count - 1,
+            entry,
+            zip_file_name,
+            extract_to,
+            options,
+            staging,
+            || classify::classify(&header.name, None),
+        );
+        let mut output_file = staged.as_ref().map(entry::create_file).transpose()?;
+
+        // Entries that are skipped still need to be read to get to the next header
+        let mut sink = io::sink();
+        let writer: &mut dyn Write = match output_file.as_mut() {
+            Some(file) => file,
+            None => &mut sink,
+        };
+        let crc32 = copy_entry_data(reader, &header, writer, &budget).map_err(|e| {
+            limits::copy_error(e, format!("Failed to extract {} from stream", header.name))
+        })?;
+
+        let expected_crc32 = if has_data_descriptor {
+            read_data_descriptor(reader, header.zip64)?
+        } else {
+            header.crc32
+        };
+        if crc32 != expected_crc32 {
+            anyhow::bail!("CRC mismatch for {} in {zip_file_name}", header.name);
+        }
+
+        if let (Some(staged), Some(file)) = (&staged, &output_file) {
+            entry::finish_file(staged, file)?;
+        }
+    }
+}
+
+/// Copy the entry data following the local file header to the writer.
+/// Returns the CRC-32 of the uncompressed data.
+fn copy_entry_data<R: BufRead>(
+    reader: &mut R,
+    header: &LocalFileHeader,
+    writer: &mut dyn Write,
+    budget: &SizeBudget,
+) -> io::Result<u32> {
+    if header.method != METHOD_DEFLATED {
+        return copy_with_crc(
+            &mut budget.reader(reader.take(header.compressed_size)),
+            writer,
+        );
+    }
+    if header.flags & FLAG_DATA_DESCRIPTOR != 0 {
+        // The compressed size is unknown, so decode until the end of the deflate stream
+        return copy_with_crc(&mut budget.reader(DeflateDecoder::new(reader)), writer);
+    }
+    let mut limited = reader.take(header.compressed_size);
+    let crc32 = copy_with_crc(
+        &mut budget.reader(DeflateDecoder::new(&mut limited)),
+        writer,
+    )?;
+    io::copy(&mut limited, &mut io::sink())?;
+    Ok(crc32)
+}
+
+/// Read the local file header fields following the signature.
+fn read_local_file_header<R: Read>(reader: &mut R) -> anyhow::Result<LocalFileHeader> {
+    let mut block = [0u8; 26];
+    reader
+        .read_exact(&mut block)
+        .context("Failed to read zip local file header")?;
+    let u16_at = |i: usize| u16::from_le_bytes([block[i], block[i + 1]]);
+    let u32_at =
+        |i: usize| u32::from_le_bytes([block[i], block[i + 1], block[i + 2], block[i + 3]]);
+
+    let flags = u16_at(2);
+    let compressed_size = u32_at(14);
+    let mut name_raw = vec![0; usize::from(u16_at(22))];
+    let mut extra = vec![0; usize::from(u16_at(24))];
+    reader.read_exact(&mut name_raw)?;
+    reader.read_exact(&mut extra)?;
+
+    let mut name = if flags & FLAG_UTF8 != 0 {
+        String::from_utf8_lossy(&name_raw).into_owned()
+    } else {
+        let cp437 = utils::decode_cp437(&name_raw);
+        utils::decode_zip_entry_name(&cp437, &name_raw).to_string()
+    };
+
+    let mut header = LocalFileHeader {
+        flags,
+        method: u16_at(4),
+        dos_time: u16_at(6),
+        dos_date: u16_at(8),
+        crc32: u32_at(10),
+        compressed_size: u64::from(compressed_size),
+        zip64: false,
+        name: String::new(),
+        modified: None,
+    };
+
+    let mut fields = extra.as_slice();
+    while fields.len() >= 4 {
+        let id = u16::from_le_bytes([fields[0], fields[1]]);
+        let len = usize::from(u16::from_le_bytes([fields[2], fields[3]]));
+        let Some(data) = fields.get(4..4 + len) else {
+            break;
+        };
+        match id {
+            EXTRA_FIELD_ZIP64 => {
+                header.zip64 = true;
+                // Uncompressed size comes first, followed by the compressed size
+                if compressed_size == ZIP64_SIZE_MARKER
+                    && let Some(size) = data.get(8..16)
+                {
+                    header.compressed_size =
+                        u64::from_le_bytes(size.try_into().expect("Slice has 8 bytes"));
+                }
+            }
+            EXTRA_FIELD_EXTENDED_TIMESTAMP => {
+                if data.first().is_some_and(|flags| flags & 1 != 0)
+                    && let Some(seconds) = data.get(1..5)
+                {
+                    let seconds =
+                        u32::from_le_bytes(seconds.try_into().expect("Slice has 4 bytes"));
+                    header.modified = Some(UNIX_EPOCH + Duration::from_secs(u64::from(seconds)));
+                }
+            }
+            EXTRA_FIELD_UNICODE_PATH => {
+                if let Some(path) = data.get(5..)
+                    && let Ok(path) = std::str::from_utf8(path)
+                {
+                    name = path.to_string();
+                }
+            }
+            _ => {}
+        }
+        fields = &fields[4 + len..];
+    }
+    header.name = name;
+    Ok(header)
+}
+
+/// Read the data descriptor following the entry data.
+/// Returns the CRC-32 value.
+fn read_data_descriptor<R: Read>(reader: &mut R, zip64: bool) -> anyhow::Result<u32> {
+    // The signature is optional
+    let mut crc32 = read_u32(reader)?;
+    if crc32 == DATA_DESCRIPTOR_SIGNATURE {
+        crc32 = read_u32(reader)?;
+    }
+    let mut sizes = vec![0; if zip64 { 16 } else { 8 }];
+    reader
+        .read_exact(&mut sizes)
+        .context("Failed to read zip data descriptor")?;
+    Ok(crc32)
+}
+
+/// Copy all data from reader to writer.
+/// Returns the CRC-32 of the data.
+fn copy_with_crc<R: Read + ?Sized>(reader: &mut R, writer: &mut dyn Write) -> io::Result<u32> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let count = reader.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buffer[..count]);
+        writer.write_all(&buffer[..count])?;
+    }
+    Ok(hasher.finalize())
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> anyhow::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader
+        .read_exact(&mut bytes)
+        .context("Unexpected end of zip stream")?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    use std::io::Cursor;
+
+    use zip::write::SimpleFileOptions;
+
+    use crate::limits::{ExtractLimits, LimitExceeded};
+
+    fn create_zip(entries: &[(&str, &[u8])], method: zip::CompressionMethod) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default().compression_method(method);
+        for (name, data) in entries {
+            writer.start_file(*name, options).expect("start file");
+            writer.write_all(data).expect("write data");
+        }
+        writer.finish().expect("finish zip").into_inner()
+    }
+
+    #[test]
+    fn extract_deflated_and_stored_entries() {
+        let entries: [(&str, &[u8]); 3] = [
+            ("Artist - Album - 01 Intro.flac", &[1; 5000]),
+            ("Artist - Album - 02 Song.aiff", b"stored data"),
+            ("cover.jpg", &[]),
+        ];
+        for method in [
+            zip::CompressionMethod::Deflated,
+            zip::CompressionMethod::Stored,
+        ] {
+            let dir = TempDir::new(&format!("{method:?}"));
+            let data = create_zip(&entries, method);
+            let extracted = extract_zip_stream(
+                data.as_slice(),
+                "album.zip",
+                &dir,
+                &ExtractOptions::default(),
+            )
+            .expect("extract stream");
+            assert_eq!(extracted.files.len(), 3);
+            assert_eq!(
+                std::fs::read(dir.join("Artist - Album - 01 Intro.flac")).expect("read"),
+                vec![1; 5000]
+            );
+            assert_eq!(
+                std::fs::read(dir.join("Artist - Album - 02 Song.aif")).expect("read"),
+                b"stored data"
+            );
+        }
+    }
+
+    #[test]
+    fn extract_entries_with_data_descriptors() {
+        let dir = TempDir::new("descriptor");
+        let mut writer = zip::ZipWriter::new_stream(Vec::new());
+        let options =
+            SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        writer
+            .start_file("Disc 1/01 Track.flac", options)
+            .expect("start file");
+        writer.write_all(&vec![7; 100_000]).expect("write data");
+        writer
+            .start_file("02 Track.flac", options)
+            .expect("start file");
+        writer.write_all(b"second").expect("write data");
+        let data = writer.finish().expect("finish zip").into_inner();
+
+        let extracted = extract_zip_stream(
+            data.as_slice(),
+            "album.zip",
+            &dir,
+            &ExtractOptions::default(),
+        )
+        .expect("extract stream");
+        assert_eq!(extracted.files.len(), 2);
+        assert_eq!(
+            std::fs::read(dir.join("Disc 1").join("01 Track.flac")).expect("read"),
+            vec![7; 100_000]
+        );
+        assert_eq!(
+            std::fs::read(dir.join("02 Track.flac")).expect("read"),
+            b"second"
+        );
+    }
+
+    #[test]
+    fn stored_entries_with_data_descriptors_require_random_access() {
+        let dir = TempDir::new("random-access");
+        let mut writer = zip::ZipWriter::new_stream(Vec::new());
+        let options =
+            SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        writer
+            .start_file("01 Track.wav", options)
+            .expect("start file");
+        writer.write_all(b"data").expect("write data");
+        let data = writer.finish().expect("finish zip").into_inner();
+
+        let error = extract_zip_stream(
+            data.as_slice(),
+            "album.zip",
+            &dir,
+            &ExtractOptions::default(),
+        )
+        .expect_err("stored entry without size");
+        assert!(error.is::<RandomAccessRequired>());
+        assert!(!dir.join("01 Track.wav").exists());
+    }
+
+    #[test]
+    fn fallback_after_written_entries_claims_names_again() {
+        let dir = TempDir::new("fallback");
+        let mut writer = zip::ZipWriter::new_stream(Vec::new());
+        let deflated =
+            SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        let stored =
+            SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        writer
+            .start_file("01 Track.flac", deflated)
+            .expect("start file");
+        writer.write_all(b"first").expect("write data");
+        writer
+            .start_file("notes.txt", deflated)
+            .expect("start file");
+        writer.write_all(b"notes").expect("write data");
+        writer
+            .start_file("02 Track.wav", stored)
+            .expect("start file");
+        writer.write_all(b"second").expect("write data");
+        let data = writer.finish().expect("finish zip").into_inner();
+
+        let options = ExtractOptions {
+            filter: crate::filter::EntryFilter::new(&[], &["*.txt".to_string()]).expect("filter"),
+            ..ExtractOptions::default()
+        };
+        let error = extract_zip_stream(data.as_slice(), "album.zip", &dir, &options)
+            .expect_err("stored entry without size");
+        assert!(error.is::<RandomAccessRequired>());
+        assert_eq!(options.filter.skipped(), 0);
+
+        // Extract the saved archive like after falling back
+        let archive = dir.join("album.zip");
+        std::fs::write(&archive, &data).expect("write archive");
+        let plan = crate::plan_archive(&archive, &options).expect("plan archive");
+        let extracted = crate::extract_planned(plan, &indicatif::MultiProgress::new(), &options)
+            .expect("extract archive");
+        assert_eq!(extracted.files.len(), 2);
+        assert_eq!(
+            std::fs::read(dir.join("01 Track.flac")).expect("read"),
+            b"first"
+        );
+        assert_eq!(
+            std::fs::read(dir.join("02 Track.wav")).expect("read"),
+            b"second"
+        );
+        assert!(options.names.collisions().is_empty());
+        assert_eq!(options.filter.skipped(), 1);
+    }
+
+    #[test]
+    fn corrupt_stream_removes_written_files() {
+        let dir = TempDir::new("corrupt");
+        let mut data = create_zip(
+            &[("a.flac", b"aaaa"), ("b.flac", b"bbbb")],
+            zip::CompressionMethod::Stored,
+        );
+        // Flip a byte in the second entry's data
+        let index = data
+            .windows(4)
+            .position(|window| window == b"bbbb")
+            .expect("find data");
+        data[index] = b'x';
+        let result = extract_zip_stream(data.as_slice(), "x.zip", &dir, &ExtractOptions::default());
+        assert!(result.is_err());
+        assert!(!dir.join("a.flac").exists());
+        assert!(!dir.join("b.flac").exists());
+    }
+
+    #[test]
+    fn size_limit_removes_written_files() {
+        let dir = TempDir::new("limit");
+        let data = create_zip(
+            &[("a.flac", &[1; 600]), ("b.flac", &[2; 600])],
+            zip::CompressionMethod::Deflated,
+        );
+        let options = ExtractOptions {
+            limits: ExtractLimits {
+                max_total_size: Some(1000),
+                ..ExtractLimits::unlimited()
+            },
+            ..ExtractOptions::default()
+        };
+        let error = extract_zip_stream(data.as_slice(), "x.zip", &dir, &options)
+            .expect_err("over the size limit");
+        assert!(error.is::<LimitExceeded>());
+        assert!(!dir.join("a.flac").exists());
+        assert!(!dir.join("b.flac").exists());
+    }
+}
@@ -0,0 +1,322 @@
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::Context;
+
+use crate::metadata::Metadata;
+use crate::staging;
+use crate::utils::FilenameOptions;
+
+/// Value used for template fields that are not known.
+const UNKNOWN_VALUE: &str = "Unknown";
+
+/// Default format for the `{date}` field.
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// Output directory template relative to the output directory,
+/// for example `Incoming/{date:%Y-%m}/{artist} - {album}`.
+///
+/// Fields are `{artist}`, `{album}`, `{title}`, `{disc}`, `{track}`, `{format}`, `{year}`, `{label}`
+/// and `{date}` for the current date, with an optional strftime format like `{date:%Y-%m}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputTemplate {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Field(Field),
+    /// Current date with a strftime format
+    Date(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Artist,
+    Album,
+    Title,
+    Disc,
+    Track,
+    Format,
+    Year,
+    Label,
+}
+
+impl OutputTemplate {
+    /// Render the directory path for the metadata.
+    /// Field values are sanitized so they can not add path components,
+    /// and `.` and `..` components are replaced so the path stays inside the output directory.
+    #[must_use]
+    pub fn render(
+        &self,
+        metadata: &Metadata,
+        now: &jiff::Zoned,
+        filenames: FilenameOptions,
+    ) -> PathBuf {
+        self.render_text(metadata, now, filenames)
+            .split('/')
+            .map(str::trim)
+            .filter(|component| !component.is_empty())
+            .map(replace_dot_name)
+            .collect()
+    }
+
+    /// Render a single filename for the metadata.
+    /// The whole name is sanitized, so separators from the text or the date are replaced too.
+    #[must_use]
+    pub fn render_filename(
+        &self,
+        metadata: &Metadata,
+        now: &jiff::Zoned,
+        filenames: FilenameOptions,
+    ) -> String {
+        let name = filenames.apply(self.render_text(metadata, now, filenames).trim());
+        replace_dot_name(&name).to_string()
+    }
+
+    /// Render the template with sanitized field values.
+    fn render_text(
+        &self,
+        metadata: &Metadata,
+        now: &jiff::Zoned,
+        filenames: FilenameOptions,
+    ) -> String {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => rendered.push_str(text),
+                Part::Field(field) => {
+                    let value = field
+                        .value(metadata)
+                        .filter(|value| !value.trim().is_empty())
+                        .unwrap_or_else(|| UNKNOWN_VALUE.to_string());
+                    rendered.push_str(&filenames.apply(&value));
+                }
+                Part::Date(format) => {
+                    let _ = write!(rendered, "{}", now.strftime(format));
+                }
+            }
+        }
+        rendered
+    }
+}
+
+/// Replace the `.` and `..` names that refer to the current or parent directory.
+fn replace_dot_name(name: &str) -> &str {
+    match name {
+        "." | ".." => "_",
+        name => name,
+    }
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "artist" => Some(Self::Artist),
+            "album" => Some(Self::Album),
+            "title" => Some(Self::Title),
+            "disc" => Some(Self::Disc),
+            "track" => Some(Self::Track),
+            "format" => Some(Self::Format),
+            "year" => Some(Self::Year),
+            "label" => Some(Self::Label),
+            _ => None,
+        }
+    }
+
+    fn value(self, metadata: &Metadata) -> Option<String> {
+        match self {
+            Self::Artist => metadata.artist.clone(),
+            Self::Album => metadata.album.clone(),
+            Self::Title => metadata.title.clone(),
+            Self::Disc => metadata.disc.map(|disc| disc.to_string()),
+            Self::Track => metadata.track.map(|track| format!("{track:02}")),
+            Self::Format => metadata.format.clone(),
+            Self::Year => metadata.year.map(|year| year.to_string()),
+            Self::Label => metadata.label.clone(),
+        }
+    }
+}
+
+impl FromStr for OutputTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        if template.starts_with('/')
+            || template
+                .split('/')
+                .any(|component| component.trim() == "..")
+        {
+            anyhow::bail!(
+                "Output template must be a relative path inside the output directory: {template}"
+            );
+        }
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .with_context(|| format!("Unclosed field in output template: {template}"))?;
+            let field = &rest[start + 1..start + end];
+            parts.push(parse_field(field)?);
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        Ok(Self { parts })
+    }
+}
+
+/// Parse a template field name without the braces.
+fn parse_field(field: &str) -> anyhow::Result<Part> {
+    let (name, format) = match field.split_once(':') {
+        Some((name, format)) => (name, Some(format)),
+        None => (field, None),
+    };
+    if name == "date" {
+        let format = format.unwrap_or(DEFAULT_DATE_FORMAT);
+        jiff::fmt::strtime::format(format, &jiff::Zoned::now())
+            .with_context(|| format!("Invalid date format in output template: {format}"))?;
+        return Ok(Part::Date(format.to_string()));
+    }
+    match (Field::parse(name), format) {
+        (Some(field), None) => Ok(Part::Field(field)),
+        (Some(_), Some(_)) => anyhow::bail!("Only the date field has a format: {{{field}}}"),
+        (None, _) => anyhow::bail!("Unknown output template field: {{{field}}}"),
+    }
+}
+
+/// Move files from the base directory to the same relative paths in the target directory.
+///
+/// Removed files and files outside the base directory are left in place,
+/// and existing files in the target directory are only replaced with `overwrite`.
+/// Directories left empty are removed.
+/// Returns the new paths in the same order.
+pub fn move_files(
+    files: &[PathBuf],
+    base: &Path,
+    target: &Path,
+    overwrite: bool,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut moved = Vec::with_capacity(files.len());
+    for file in files {
+        let relative = match file.strip_prefix(base) {
+            Ok(relative) if file.exists() => relative,
+            _ => {
+                moved.push(file.clone());
+                continue;
+            }
+        };
+        let destination = target.join(relative);
+        if destination == *file || (destination.exists() && !overwrite) {
+            moved.push(file.clone());
+            continue;
+        }
+        staging::move_file(file, &destination)?;
+        remove_empty_parents(file, base);
+        moved.push(destination);
+    }
+    Ok(moved)
+}
+
+/// Remove the empty parent directories of a moved file up to the base directory.
+fn remove_empty_parents(file: &Path, base: &Path) {
+    let mut dir = file.parent();
+    while let Some(parent) = dir {
+        if parent == base || !parent.starts_with(base) || std::fs::remove_dir(parent).is_err() {
+            break;
+        }
+        dir = parent.parent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    fn metadata() -> Metadata {
+        Metadata {
+            artist: Some("AC/DC".to_string()),
+            album: Some("Live".to_string()),
+            format: Some("flac".to_string()),
+            year: Some(1992),
+            ..Metadata::default()
+        }
+    }
+
+    #[test]
+    fn render_template() {
+        let now: jiff::Zoned = "2026-10-18T12:00:00+00:00[UTC]".parse().expect("date");
+        let template: OutputTemplate = "Incoming/{date:%Y-%m}/{artist} - {album} ({year})/{format}"
+            .parse()
+            .expect("template");
+        assert_eq!(
+            template.render(&metadata(), &now, FilenameOptions::default()),
+            Path::new("Incoming/2026-10/AC_DC - Live (1992)/flac")
+        );
+
+        let template: OutputTemplate = "{label}/{date}".parse().expect("template");
+        assert_eq!(
+            template.render(&metadata(), &now, FilenameOptions::default()),
+            Path::new("Unknown/2026-10-18")
+        );
+    }
+
+    #[test]
+    fn render_dot_values_inside_output_directory() {
+        let now: jiff::Zoned = "2026-10-18T12:00:00+00:00[UTC]".parse().expect("date");
+        let metadata = Metadata {
+            artist: Some("..".to_string()),
+            album: Some(".".to_string()),
+            ..Metadata::default()
+        };
+        let template: OutputTemplate = "{artist}/{album}/{artist}".parse().expect("template");
+        assert_eq!(
+            template.render(&metadata, &now, FilenameOptions::default()),
+            Path::new("_/_/_")
+        );
+
+        let template: OutputTemplate = "{date:%D} {album}".parse().expect("template");
+        assert_eq!(
+            template.render_filename(&metadata, &now, FilenameOptions::default()),
+            "10_18_26 ."
+        );
+        let template: OutputTemplate = "{artist}".parse().expect("template");
+        assert_eq!(
+            template.render_filename(&metadata, &now, FilenameOptions::default()),
+            "_"
+        );
+    }
+
+    #[test]
+    fn invalid_templates() {
+        assert!("{artist".parse::<OutputTemplate>().is_err());
+        assert!("{genre}".parse::<OutputTemplate>().is_err());
+        assert!("{artist:%Y}".parse::<OutputTemplate>().is_err());
+        assert!("../{artist}".parse::<OutputTemplate>().is_err());
+        assert!("/music/{artist}".parse::<OutputTemplate>().is_err());
+    }
+
+    #[test]
+    fn move_files_to_target() {
+        let base = TempDir::new("template");
+        std::fs::create_dir_all(base.join("Disc 1")).expect("create dir");
+        let files = [base.join("Disc 1").join("01.flac"), base.join("02.flac")];
+        for file in &files {
+            std::fs::write(file, b"audio").expect("write file");
+        }
+
+        let target = base.join("Artist - Album");
+        let moved = move_files(&files, &base, &target, false).expect("move files");
+        assert_eq!(moved[0], target.join("Disc 1").join("01.flac"));
+        assert!(moved.iter().all(|file| file.is_file()));
+        assert!(!base.join("Disc 1").exists());
+    }
+}
@@ -1,7 +1,12 @@
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::{env, fs};
 
 use anyhow::Context;
+use clap::ValueEnum;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::archive::ArchiveFormat;
 
 /// Resolves the provided path to a directory or file to an absolute path.
 ///
@@ -68,11 +73,12 @@ pub fn remove_images_from_dir(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
     Ok(removed)
 }
 
-/// Get all zip files from the given paths.
-pub fn get_all_zip_files(paths: &[PathBuf]) -> Vec<PathBuf> {
+/// Get all supported archives from the given downloaded files.
+/// Archives are detected from the file contents, so the file extension does not matter.
+pub fn get_all_archives(paths: &[PathBuf]) -> Vec<PathBuf> {
     paths
         .iter()
-        .filter(|path| path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("zip"))
+        .filter(|path| path.is_file() && ArchiveFormat::sniff(path).is_some())
         .map(PathBuf::from)
         .collect()
 }
@@ -99,7 +105,7 @@ pub fn resolve_output_path(path: Option<&str>) -> anyhow::Result<PathBuf> {
 
 /// Remove all images from given directory.
 /// Print removed files with the verbose flag.
-pub fn remove_images(directory: &Path, verbose: bool) -> anyhow::Result<usize> {
+pub fn remove_images(directory: &Path, verbose: bool) -> anyhow::Result<Vec<PathBuf>> {
     let removed = remove_images_from_dir(directory)?;
     if !removed.is_empty() && verbose {
         println!("Removed images ({}):", removed.len());
@@ -110,7 +116,7 @@ pub fn remove_images(directory: &Path, verbose: bool) -> anyhow::Result<usize> {
             );
         }
     }
-    Ok(removed.len())
+    Ok(removed)
 }
 
 /// Get filename string for given Path.
@@ -123,6 +129,84 @@ pub fn get_filename_from_path(path: &Path) -> anyhow::Result<String> {
     Ok(file_name)
 }
 
+/// Filename sanitization rules for the target filesystem.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum SanitizeProfile {
+    /// Replace characters that are invalid on common platforms, limit names to 255 bytes
+    #[default]
+    Posix,
+    /// Posix rules plus Windows reserved names and trailing dots and spaces, limit names to 255 UTF-16 units
+    Windows,
+    /// Windows rules plus replacing characters outside the Basic Multilingual Plane
+    Fat32,
+    /// FAT32 rules plus replacing all non-ASCII characters
+    Ascii,
+}
+
+/// Windows reserved device names that can not be used as a filename, with or without an extension.
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Unicode normalization form for output filenames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum UnicodeForm {
+    /// Canonical composition, used by Linux and Windows
+    Nfc,
+    /// Canonical decomposition, used by older macOS filesystems
+    Nfd,
+}
+
+/// Options for building output filenames.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FilenameOptions {
+    /// Filename sanitization profile
+    pub sanitize: SanitizeProfile,
+    /// Transliterate filenames to ASCII
+    pub transliterate: bool,
+    /// Unicode normalization form
+    pub normalize: Option<UnicodeForm>,
+}
+
+impl FilenameOptions {
+    /// Build the output filename for the given name.
+    #[must_use]
+    pub fn apply(&self, name: &str) -> String {
+        let name: String = match self.normalize {
+            Some(UnicodeForm::Nfc) => name.nfc().collect(),
+            Some(UnicodeForm::Nfd) => name.nfd().collect(),
+            None => name.to_string(),
+        };
+        if self.transliterate {
+            sanitize_filename_with_profile(&transliterate_filename(&name), self.sanitize)
+        } else {
+            sanitize_filename_with_profile(&name, self.sanitize)
+        }
+    }
+}
+
+/// Hidden file in the output directory that maps transliterated filenames back to the originals.
+pub const ORIGINAL_NAMES_FILE: &str = ".bcdl-original-names.jsonl";
+
+/// Characters for the bytes 0x80-0xFF in code page 437.
+const CP437_HIGH_CHARACTERS: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', //
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', //
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', //
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', //
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', //
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', //
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// Maximum filename length in bytes for POSIX, or UTF-16 code units for Windows and FAT32.
+const MAX_FILENAME_LENGTH: usize = 255;
+
+/// Longest extension kept intact when truncating a filename.
+const MAX_PRESERVED_EXTENSION_LENGTH: usize = 16;
+
 /// Sanitize filename by replacing invalid characters with underscores for cross-platform compatibility.
 ///
 /// Windows invalid: `< > : " / \ | ? *`
@@ -137,3 +221,433 @@ pub fn sanitize_filename(filename: &str) -> String {
         // Replace nulls and control characters
         .replace(|c: char| c.is_control(), "_")
 }
+
+/// Sanitize filename according to the given profile.
+///
+/// Names that exceed the length limit are truncated while keeping the extension,
+/// and get a short hash of the original name appended so that different long names stay unique.
+#[must_use]
+pub fn sanitize_filename_with_profile(filename: &str, profile: SanitizeProfile) -> String {
+    let mut name = sanitize_filename(filename);
+    if matches!(profile, SanitizeProfile::Fat32 | SanitizeProfile::Ascii) {
+        name = name.replace(|c: char| u32::from(c) > 0xFFFF, "_");
+    }
+    if profile == SanitizeProfile::Ascii {
+        name = name.replace(|c: char| !c.is_ascii(), "_");
+    }
+    if profile != SanitizeProfile::Posix {
+        name = sanitize_windows_filename(&name);
+    }
+    if name.is_empty() {
+        name.push('_');
+    }
+
+    let length = |s: &str| match profile {
+        SanitizeProfile::Posix => s.len(),
+        _ => s.encode_utf16().count(),
+    };
+    if length(&name) > MAX_FILENAME_LENGTH {
+        name = truncate_filename(&name, &format!("~{:08x}", fnv1a_hash(filename)), length);
+    }
+    name
+}
+
+/// Transliterate Unicode characters in filename to the closest readable ASCII representation.
+///
+/// Characters without a known transliteration are replaced with underscores.
+#[must_use]
+pub fn transliterate_filename(filename: &str) -> String {
+    if filename.is_ascii() {
+        return filename.to_string();
+    }
+    // Transliteration separates words with spaces,
+    // so collapse repeated whitespace and remove spaces left before the extension.
+    let mut name = deunicode::deunicode_with_tofu(filename, "_")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    if let Some(index) = name.rfind('.')
+        && name[..index].ends_with(' ')
+    {
+        name.remove(index - 1);
+    }
+    name
+}
+
+/// Decode a zip entry name, fixing names that were decoded with the wrong encoding.
+///
+/// Entries without the UTF-8 flag are decoded as CP437 per the zip specification,
+/// but many archivers store UTF-8 names without setting the flag.
+/// If the raw name is valid UTF-8 and contains non-ASCII bytes, it is used instead.
+#[must_use]
+pub fn decode_zip_entry_name<'a>(name: &'a str, raw: &'a [u8]) -> &'a str {
+    match std::str::from_utf8(raw) {
+        Ok(utf8) if !raw.is_ascii() && utf8 != name => utf8,
+        _ => name,
+    }
+}
+
+/// Decode bytes using the IBM code page 437, the default encoding for zip entry names.
+#[must_use]
+pub fn decode_cp437(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&byte| {
+            if byte.is_ascii() {
+                char::from(byte)
+            } else {
+                CP437_HIGH_CHARACTERS[usize::from(byte - 0x80)]
+            }
+        })
+        .collect()
+}
+
+/// Split a zip entry name into path components that stay inside the extraction directory.
+///
+/// Returns `None` for absolute paths, names containing NULL bytes,
+/// or paths that would resolve outside the current directory.
+#[must_use]
+pub fn enclosed_zip_entry_components(name: &str) -> Option<Vec<&str>> {
+    if name.contains('\0') || name.starts_with(['/', '\\']) {
+        return None;
+    }
+    let mut components = Vec::new();
+    for component in name.split(['/', '\\']) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop()?;
+            }
+            _ => components.push(component),
+        }
+    }
+    Some(components)
+}
+
+/// Append the original name of a renamed file to the original names file in the given directory.
+pub fn record_original_name(dir: &Path, name: &Path, original: &str) -> anyhow::Result<()> {
+    let path = dir.join(ORIGINAL_NAMES_FILE);
+    let line = serde_json::json!({
+        "name": name.to_string_lossy(),
+        "original": original,
+    });
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open original names file: {}", path.display()))?;
+    file.write_all(format!("{line}\n").as_bytes())
+        .with_context(|| format!("Failed to write original names file: {}", path.display()))?;
+    Ok(())
+}
+
+/// Read the renamed files and their original names from the original names file
+/// in the given directory. Returns an empty list if the file does not exist.
+#[cfg(test)]
+pub(crate) fn read_original_names(dir: &Path) -> anyhow::Result<Vec<(PathBuf, String)>> {
+    let path = dir.join(ORIGINAL_NAMES_FILE);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(e).with_context(|| {
+                format!("Failed to read original names file: {}", path.display())
+            });
+        }
+    };
+    let mut names = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let value: serde_json::Value = serde_json::from_str(line).with_context(|| {
+            format!(
+                "Invalid original names line {}: {}",
+                index + 1,
+                path.display()
+            )
+        })?;
+        let (Some(name), Some(original)) = (value["name"].as_str(), value["original"].as_str())
+        else {
+            anyhow::bail!(
+                "Invalid original names line {}: {}",
+                index + 1,
+                path.display()
+            );
+        };
+        names.push((PathBuf::from(name), original.to_string()));
+    }
+    Ok(names)
+}
+
+/// Handle Windows reserved device names and trailing dots and spaces.
+fn sanitize_windows_filename(filename: &str) -> String {
+    let mut name = filename.trim_end_matches(['.', ' ']).to_string();
+    let base = name.split('.').next().unwrap_or_default().trim_end();
+    if WINDOWS_RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(base))
+    {
+        let index = base.len();
+        name.insert(index, '_');
+    }
+    name
+}
+
+/// Shorten filename to fit the maximum length,
+/// adding the given suffix between the truncated stem and the extension.
+fn truncate_filename(filename: &str, suffix: &str, length: impl Fn(&str) -> usize) -> String {
+    let (stem, extension) = match filename.rfind('.') {
+        Some(index) if index > 0 && filename.len() - index <= MAX_PRESERVED_EXTENSION_LENGTH => {
+            filename.split_at(index)
+        }
+        _ => (filename, ""),
+    };
+    let budget = MAX_FILENAME_LENGTH.saturating_sub(length(suffix) + length(extension));
+    let mut truncated = String::new();
+    for c in stem.chars() {
+        let mut buffer = [0; 4];
+        if length(&truncated) + length(c.encode_utf8(&mut buffer)) > budget {
+            break;
+        }
+        truncated.push(c);
+    }
+    // Windows does not allow trailing spaces or dots before the suffix either
+    let truncated = truncated.trim_end_matches(['.', ' ']);
+    format!("{truncated}{suffix}{extension}")
+}
+
+/// Stable 32-bit FNV-1a hash for generating short deterministic filename suffixes.
+#[must_use]
+pub fn fnv1a_hash(value: &str) -> u32 {
+    value.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Quote the argument for a POSIX shell if needed.
+#[must_use]
+pub fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c))
+    {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// Parse a human-readable byte size like `512M`, `1.5GiB` or `4096`.
+/// Units use binary multiples of 1024 and are case-insensitive.
+pub fn parse_size(value: &str) -> anyhow::Result<u64> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number
+        .parse()
+        .with_context(|| format!("Invalid size: '{value}'"))?;
+    let exponent = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 0,
+        "k" | "kb" | "kib" => 1,
+        "m" | "mb" | "mib" => 2,
+        "g" | "gb" | "gib" => 3,
+        "t" | "tb" | "tib" => 4,
+        _ => anyhow::bail!("Invalid size unit: '{value}'"),
+    };
+    let bytes = number * 1024_f64.powi(exponent);
+    if !bytes.is_finite() || bytes >= 2_f64.powi(64) {
+        anyhow::bail!("Size is too large: '{value}'");
+    }
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    Ok(bytes as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn original_names_round_trip() {
+        let dir = TempDir::new("original-names");
+        assert!(
+            read_original_names(&dir)
+                .expect("read missing file")
+                .is_empty()
+        );
+        record_original_name(&dir, Path::new("a_b.flac"), "a:b.flac").expect("record name");
+        record_original_name(&dir, Path::new("Album/c_.flac"), "Album/c?.flac")
+            .expect("record name");
+        assert_eq!(
+            read_original_names(&dir).expect("read names"),
+            vec![
+                (PathBuf::from("a_b.flac"), "a:b.flac".to_string()),
+                (PathBuf::from("Album/c_.flac"), "Album/c?.flac".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn sanitize_replaces_invalid_characters() {
+        assert_eq!(sanitize_filename("a:b/c?.flac"), "a_b_c_.flac");
+        assert_eq!(sanitize_filename("say \"hi\".mp3"), "say ''hi''.mp3");
+    }
+
+    #[test]
+    fn posix_profile_keeps_reserved_names() {
+        assert_eq!(
+            sanitize_filename_with_profile("CON.flac", SanitizeProfile::Posix),
+            "CON.flac"
+        );
+        assert_eq!(
+            sanitize_filename_with_profile("Album...", SanitizeProfile::Posix),
+            "Album..."
+        );
+    }
+
+    #[test]
+    fn windows_profile_handles_reserved_names() {
+        let profile = SanitizeProfile::Windows;
+        assert_eq!(sanitize_filename_with_profile("CON", profile), "CON_");
+        assert_eq!(
+            sanitize_filename_with_profile("nul.txt", profile),
+            "nul_.txt"
+        );
+        assert_eq!(
+            sanitize_filename_with_profile("Com1.tar.gz", profile),
+            "Com1_.tar.gz"
+        );
+        assert_eq!(
+            sanitize_filename_with_profile("CONTROL.txt", profile),
+            "CONTROL.txt"
+        );
+    }
+
+    #[test]
+    fn windows_profile_trims_trailing_dots_and_spaces() {
+        let profile = SanitizeProfile::Windows;
+        assert_eq!(
+            sanitize_filename_with_profile("Vol. 2. ", profile),
+            "Vol. 2"
+        );
+        assert_eq!(sanitize_filename_with_profile("...", profile), "_");
+    }
+
+    #[test]
+    fn fat32_and_ascii_profiles_replace_characters() {
+        assert_eq!(
+            sanitize_filename_with_profile("Café 🎵.mp3", SanitizeProfile::Fat32),
+            "Café _.mp3"
+        );
+        assert_eq!(
+            sanitize_filename_with_profile("Café 🎵.mp3", SanitizeProfile::Ascii),
+            "Caf_ _.mp3"
+        );
+    }
+
+    #[test]
+    fn transliterate_to_ascii() {
+        assert_eq!(
+            transliterate_filename("Björk - Jóga.flac"),
+            "Bjork - Joga.flac"
+        );
+        assert_eq!(
+            transliterate_filename("Кино - Группа крови.mp3"),
+            "Kino - Gruppa krovi.mp3"
+        );
+        assert_eq!(transliterate_filename("東京.aif"), "Dong Jing.aif");
+        assert_eq!(
+            transliterate_filename("Plain  ASCII.wav"),
+            "Plain  ASCII.wav"
+        );
+    }
+
+    #[test]
+    fn normalize_unicode_form() {
+        let decomposed = "Cafe\u{301}.flac";
+        let composed = "Caf\u{e9}.flac";
+        let nfc = FilenameOptions {
+            normalize: Some(UnicodeForm::Nfc),
+            ..FilenameOptions::default()
+        };
+        let nfd = FilenameOptions {
+            normalize: Some(UnicodeForm::Nfd),
+            ..FilenameOptions::default()
+        };
+        assert_eq!(nfc.apply(decomposed), composed);
+        assert_eq!(nfc.apply(composed), composed);
+        assert_eq!(nfd.apply(composed), decomposed);
+        assert_eq!(FilenameOptions::default().apply(decomposed), decomposed);
+    }
+
+    #[test]
+    fn decode_mis_encoded_zip_entry_names() {
+        let raw = "Sigur Rós/Ágætis byrjun.flac".as_bytes();
+        // What the CP437 decoder produces for UTF-8 bytes stored without the UTF-8 flag
+        let cp437: String = raw
+            .iter()
+            .map(|&b| if b < 0x80 { char::from(b) } else { '\u{2592}' })
+            .collect();
+        assert_eq!(
+            decode_zip_entry_name(&cp437, raw),
+            "Sigur Rós/Ágætis byrjun.flac"
+        );
+
+        // Genuine CP437 bytes are not valid UTF-8 and keep the decoded name
+        let raw = [b'M', 0x81, b'z', b'i', b'k'];
+        assert_eq!(decode_cp437(&raw), "M\u{fc}zik");
+        assert_eq!(decode_zip_entry_name("M\u{fc}zik", &raw), "M\u{fc}zik");
+        assert_eq!(
+            decode_zip_entry_name("plain.mp3", b"plain.mp3"),
+            "plain.mp3"
+        );
+    }
+
+    #[test]
+    fn zip_entry_components_stay_enclosed() {
+        assert_eq!(
+            enclosed_zip_entry_components("Album/./Disc 1/01.flac"),
+            Some(vec!["Album", "Disc 1", "01.flac"])
+        );
+        assert_eq!(
+            enclosed_zip_entry_components("a/../b.mp3"),
+            Some(vec!["b.mp3"])
+        );
+        assert_eq!(enclosed_zip_entry_components("../b.mp3"), None);
+        assert_eq!(enclosed_zip_entry_components("/etc/passwd"), None);
+        assert_eq!(enclosed_zip_entry_components("a\\..\\..\\b"), None);
+    }
+
+    #[test]
+    fn long_names_are_truncated_with_extension() {
+        let long = format!("{}.flac", "ä".repeat(200));
+        let posix = sanitize_filename_with_profile(&long, SanitizeProfile::Posix);
+        assert!(posix.len() <= MAX_FILENAME_LENGTH);
+        assert_eq!(Path::new(&posix).extension(), Some("flac".as_ref()));
+
+        let windows = sanitize_filename_with_profile(&long, SanitizeProfile::Windows);
+        assert_eq!(windows, long);
+    }
+
+    #[test]
+    fn truncated_names_stay_unique() {
+        let first = format!("{}1.wav", "a".repeat(300));
+        let second = format!("{}2.wav", "a".repeat(300));
+        let first = sanitize_filename_with_profile(&first, SanitizeProfile::Windows);
+        let second = sanitize_filename_with_profile(&second, SanitizeProfile::Windows);
+        assert_eq!(first.encode_utf16().count(), MAX_FILENAME_LENGTH);
+        assert_eq!(Path::new(&first).extension(), Some("wav".as_ref()));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("512M").unwrap(), 512 * 1024 * 1024);
+        assert_eq!(parse_size("1.5GiB").unwrap(), 3 * 512 * 1024 * 1024);
+        assert_eq!(parse_size("2 kb").unwrap(), 2048);
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("").is_err());
+    }
+}